@@ -1,19 +1,39 @@
+use std::borrow::Borrow;
 use std::cmp::{Ord, max};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 
 type Link<K, V> = Option<Box<Node<K, V>>>;
 
+// The parts of a subtree split around a key: entries below it, the entry equal to it and
+// entries above it.
+type Split<K, V> = (Link<K, V>, Option<(K, V)>, Link<K, V>);
+
 /// Node in the AVL tree.
 pub struct Node<K: Ord, V> {
     pub key: K,
     pub value: V,
     height: i32,
+    size: usize,
     pub left: Link<K, V>,
     pub right: Link<K, V>,
 }
 
 /// Generic AVL tree that supports JIT specialization via native compilation.
 pub struct AvlTree<K: Ord, V> {
-    pub root: Link<K, V>,
+    pub(crate) root: Link<K, V>,
+    version: u64,
+}
+
+// Versions are drawn from a single global counter so that a version number identifies one
+// particular state of one particular tree.
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, AtomicOrdering::Relaxed)
 }
 
 impl<K, V> Node<K, V>
@@ -25,6 +45,7 @@ where
             key,
             value,
             height: 1,
+            size: 1,
             left: None,
             right: None,
         }
@@ -34,59 +55,513 @@ where
         node.as_ref().map_or(0, |n| n.height)
     }
 
+    /// Number of nodes in the subtree rooted at `node`.
+    pub(crate) fn size(node: &Link<K, V>) -> usize {
+        node.as_ref().map_or(0, |n| n.size)
+    }
+
     fn balance_factor(&self) -> i32 {
         Node::height(&self.left) - Node::height(&self.right)
     }
 
     fn update_height(&mut self) {
         self.height = 1 + max(Node::height(&self.left), Node::height(&self.right));
+        self.size = 1 + Node::size(&self.left) + Node::size(&self.right);
+    }
+}
+
+impl<K: Ord, V> Default for AvlTree<K, V> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl<K: Ord + Copy, V: Copy> AvlTree<K, V> {
+impl<K: Ord, V> AvlTree<K, V> {
     pub fn new() -> Self {
-        AvlTree { root: None }
+        AvlTree {
+            root: None,
+            version: next_version(),
+        }
+    }
+
+    /// Builds a tree from entries sorted by key in O(n).
+    ///
+    /// The result has minimum height. When the same key appears several times in a row the
+    /// last value wins. Panics if the keys are not in ascending order.
+    pub fn from_sorted_vec(mut entries: Vec<(K, V)>) -> Self {
+        assert!(
+            entries.is_sorted_by(|a, b| a.0 <= b.0),
+            "from_sorted_vec input is not sorted"
+        );
+        // Duplicates are dropped in place; the surviving entry takes the value of each later one
+        entries.dedup_by(|later, earlier| {
+            let duplicate = later.0 == earlier.0;
+            if duplicate {
+                std::mem::swap(&mut later.1, &mut earlier.1);
+            }
+            duplicate
+        });
+
+        let len = entries.len();
+        AvlTree {
+            root: Self::build_sorted(&mut entries.into_iter(), len),
+            version: next_version(),
+        }
+    }
+
+    /// Builds a tree from entries sorted by key in O(n), see `from_sorted_vec`. The entries are
+    /// collected into a single buffer first.
+    pub fn from_sorted_iter<I: IntoIterator<Item = (K, V)>>(entries: I) -> Self {
+        Self::from_sorted_vec(entries.into_iter().collect())
+    }
+
+    // Builds a perfectly balanced subtree out of the next `len` entries. The two halves differ
+    // in size by at most one, so their heights do too.
+    fn build_sorted(entries: &mut impl Iterator<Item = (K, V)>, len: usize) -> Link<K, V> {
+        if len == 0 {
+            return None;
+        }
+
+        let left_len = len / 2;
+        let left = Self::build_sorted(entries, left_len);
+        let (key, value) = entries.next().unwrap();
+        let right = Self::build_sorted(entries, len - left_len - 1);
+
+        let mut node = Box::new(Node::new(key, value));
+        node.left = left;
+        node.right = right;
+        node.update_height();
+        Some(node)
+    }
+
+    /// Returns the current version of the tree.
+    ///
+    /// The version changes on every mutation (insertion, removal or handing out mutable
+    /// access to values), so native code compiled from the tree at an earlier version is stale
+    /// once the two no longer match and must be recompiled.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the root node of the tree, or `None` if it is empty.
+    ///
+    /// The nodes can only be read: changes go through the methods of the tree, which keep it
+    /// balanced and its version current.
+    pub fn root(&self) -> Option<&Node<K, V>> {
+        self.root.as_deref()
+    }
+
+    pub fn lookup<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        V: Copy,
+    {
+        self.get(key).copied()
+    }
+
+    /// Returns a reference to the value stored under `key`.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get_key_value(key).map(|(_, value)| value)
     }
 
-    pub fn lookup(&self, key: &K) -> Option<V> {
+    /// Returns the stored key and a reference to the value stored under `key`.
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
         let mut current = &self.root;
         while let Some(node) = current {
-            match key.cmp(&node.key) {
+            match key.cmp(node.key.borrow()) {
                 std::cmp::Ordering::Less => current = &node.left,
                 std::cmp::Ordering::Greater => current = &node.right,
-                std::cmp::Ordering::Equal => return Some(node.value),
+                std::cmp::Ordering::Equal => return Some((&node.key, &node.value)),
+            }
+        }
+        None
+    }
+
+    /// Returns a mutable reference to the value stored under `key`.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        // The value may be rewritten through the returned reference
+        self.version = next_version();
+        let mut current = &mut self.root;
+        while let Some(node) = current {
+            match key.cmp(node.key.borrow()) {
+                std::cmp::Ordering::Less => current = &mut node.left,
+                std::cmp::Ordering::Greater => current = &mut node.right,
+                std::cmp::Ordering::Equal => return Some(&mut node.value),
             }
         }
         None
     }
 
-    pub fn insert(&mut self, key: K, value: V) {
-        self.root = Self::insert_rec(self.root.take(), key, value);
+    /// Returns true if the tree contains `key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Inserts a key-value pair, returning the previous value if the key was already present.
+    ///
+    /// The stored key is kept in that case, only the value is replaced.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let (root, previous) = Self::insert_rec(self.root.take(), key, value);
+        self.root = root;
+        self.version = next_version();
+        previous
+    }
+
+    /// Gets the entry for `key` for in-place manipulation.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+        // The entry hands out mutable access to the value
+        self.version = next_version();
+
+        let mut rank = 0;
+        let mut current = self.root.as_deref_mut();
+        while let Some(node) = current {
+            match key.cmp(&node.key) {
+                std::cmp::Ordering::Less => current = node.left.as_deref_mut(),
+                std::cmp::Ordering::Greater => {
+                    rank += Node::size(&node.left) + 1;
+                    current = node.right.as_deref_mut();
+                }
+                std::cmp::Ordering::Equal => {
+                    let node = NonNull::from(node);
+                    return Entry::Occupied(OccupiedEntry {
+                        key,
+                        node,
+                        tree: self,
+                    });
+                }
+            }
+        }
+        Entry::Vacant(VacantEntry {
+            key,
+            rank,
+            tree: self,
+        })
+    }
+
+    /// Removes a key from the tree, returning its value if it was present.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.remove_entry(key).map(|(_, value)| value)
+    }
+
+    /// Removes a key from the tree, returning the stored key and value if it was present.
+    pub fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (root, removed) = Self::remove_rec(self.root.take(), key);
+        self.root = root;
+        if removed.is_some() {
+            self.version = next_version();
+        }
+        removed
+    }
+
+    /// Joins two trees around a middle entry in O(|height(left) - height(right)|).
+    ///
+    /// Panics unless every key of `left` is less than `key` and every key of `right` is
+    /// greater than it.
+    pub fn join(mut left: Self, key: K, value: V, mut right: Self) -> Self {
+        assert!(
+            left.last_key_value().is_none_or(|(last, _)| *last < key),
+            "join keys of the left tree must be less than the middle key"
+        );
+        assert!(
+            right
+                .first_key_value()
+                .is_none_or(|(first, _)| *first > key),
+            "join keys of the right tree must be greater than the middle key"
+        );
+
+        AvlTree {
+            root: Self::join_rec(left.root.take(), key, value, right.root.take()),
+            version: next_version(),
+        }
+    }
+
+    /// Splits the tree around `key` in O(log n), returning the entries below it, the value
+    /// stored under it, and the entries above it.
+    pub fn split<Q>(mut self, key: &Q) -> (Self, Option<V>, Self)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (left, found, right) = Self::split_rec(self.root.take(), key);
+        let left = AvlTree {
+            root: left,
+            version: next_version(),
+        };
+        let right = AvlTree {
+            root: right,
+            version: next_version(),
+        };
+        (left, found.map(|(_, value)| value), right)
+    }
+
+    /// Moves all entries of `other` into the tree, leaving `other` empty.
+    ///
+    /// Values from `other` replace the values of keys present in both trees. The trees are
+    /// merged by splitting and joining subtrees rather than inserting entries one by one.
+    pub fn append(&mut self, other: &mut Self) {
+        let other_root = other.root.take();
+        other.version = next_version();
+        self.root = Self::union(self.root.take(), other_root);
+        self.version = next_version();
     }
 
-    // Returns the new root of the subtree
-    fn insert_rec(mut node: Link<K, V>, key: K, value: V) -> Link<K, V> {
+    /// Splits the tree at `key`, returning the entries with keys greater than or equal to it
+    /// and keeping the rest.
+    pub fn split_off<Q>(&mut self, key: &Q) -> Self
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (left, found, right) = Self::split_rec(self.root.take(), key);
+        self.root = left;
+        self.version = next_version();
+
+        // Joining with an empty left tree puts the split key back as the minimum
+        let right = match found {
+            Some((key, value)) => Self::join_rec(None, key, value, right),
+            None => right,
+        };
+        AvlTree {
+            root: right,
+            version: next_version(),
+        }
+    }
+
+    /// Removes and returns the entry with the smallest key.
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        let (root, min) = Self::remove_min(self.root.take()?);
+        self.root = root;
+        self.version = next_version();
+        Some((min.key, min.value))
+    }
+
+    /// Removes and returns the entry with the largest key.
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        let (root, max) = Self::remove_max(self.root.take()?);
+        self.root = root;
+        self.version = next_version();
+        Some((max.key, max.value))
+    }
+
+    // Returns the new root of the subtree and the value previously stored under the key
+    fn insert_rec(mut node: Link<K, V>, key: K, value: V) -> (Link<K, V>, Option<V>) {
         let mut node = match node.take() {
             Some(n) => n,
-            None => return Some(Box::new(Node::new(key, value))),
+            None => return (Some(Box::new(Node::new(key, value))), None),
         };
 
         match key.cmp(&node.key) {
             std::cmp::Ordering::Less => {
-                node.left = Self::insert_rec(node.left.take(), key, value);
+                let (left, previous) = Self::insert_rec(node.left.take(), key, value);
+                node.left = left;
+                if previous.is_some() {
+                    return (Some(node), previous);
+                }
             }
             std::cmp::Ordering::Greater => {
-                node.right = Self::insert_rec(node.right.take(), key, value);
+                let (right, previous) = Self::insert_rec(node.right.take(), key, value);
+                node.right = right;
+                if previous.is_some() {
+                    return (Some(node), previous);
+                }
             }
             std::cmp::Ordering::Equal => {
                 // Key already exists, update value
-                node.value = value;
-                return Some(node);
+                let previous = std::mem::replace(&mut node.value, value);
+                return (Some(node), Some(previous));
+            }
+        }
+
+        node.update_height();
+        (Self::balance(node), None)
+    }
+
+    // Returns the new root of the subtree and the removed entry, if any
+    fn remove_rec<Q>(node: Link<K, V>, key: &Q) -> (Link<K, V>, Option<(K, V)>)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut node = match node {
+            Some(n) => n,
+            None => return (None, None),
+        };
+
+        let removed = match key.cmp(node.key.borrow()) {
+            std::cmp::Ordering::Less => {
+                let (left, removed) = Self::remove_rec(node.left.take(), key);
+                node.left = left;
+                removed
+            }
+            std::cmp::Ordering::Greater => {
+                let (right, removed) = Self::remove_rec(node.right.take(), key);
+                node.right = right;
+                removed
+            }
+            std::cmp::Ordering::Equal => {
+                let Node {
+                    key,
+                    value,
+                    left,
+                    right,
+                    ..
+                } = *node;
+                let mut successor = match (left, right) {
+                    (None, child) | (child, None) => return (child, Some((key, value))),
+                    // Two children: the in-order successor takes the place of the removed node
+                    (Some(left), Some(right)) => {
+                        let (rest, mut successor) = Self::remove_min(right);
+                        successor.left = Some(left);
+                        successor.right = rest;
+                        successor
+                    }
+                };
+                successor.update_height();
+                return (Self::balance(successor), Some((key, value)));
+            }
+        };
+
+        if removed.is_none() {
+            return (Some(node), None);
+        }
+
+        node.update_height();
+        (Self::balance(node), removed)
+    }
+
+    // Detaches the leftmost node of the subtree, returning the rebalanced remainder and the node
+    fn remove_min(mut node: Box<Node<K, V>>) -> (Link<K, V>, Box<Node<K, V>>) {
+        match node.left.take() {
+            None => (node.right.take(), node),
+            Some(left) => {
+                let (rest, min) = Self::remove_min(left);
+                node.left = rest;
+                node.update_height();
+                (Self::balance(node), min)
+            }
+        }
+    }
+
+    // Detaches the rightmost node of the subtree, returning the rebalanced remainder and the node
+    fn remove_max(mut node: Box<Node<K, V>>) -> (Link<K, V>, Box<Node<K, V>>) {
+        match node.right.take() {
+            None => (node.left.take(), node),
+            Some(right) => {
+                let (rest, max) = Self::remove_max(right);
+                node.right = rest;
+                node.update_height();
+                (Self::balance(node), max)
             }
         }
+    }
+
+    // Joins two subtrees around a middle entry. The entry is hung off the spine of the taller
+    // subtree at the height of the shorter one, and the path back up is rebalanced like after
+    // an insertion, since the spine grows by at most one level.
+    fn join_rec(left: Link<K, V>, key: K, value: V, right: Link<K, V>) -> Link<K, V> {
+        let left_height = Node::height(&left);
+        let right_height = Node::height(&right);
+
+        if left_height > right_height + 1 {
+            let mut left = left.unwrap();
+            left.right = Self::join_rec(left.right.take(), key, value, right);
+            left.update_height();
+            return Self::balance(left);
+        }
+        if right_height > left_height + 1 {
+            let mut right = right.unwrap();
+            right.left = Self::join_rec(left, key, value, right.left.take());
+            right.update_height();
+            return Self::balance(right);
+        }
 
+        let mut node = Box::new(Node::new(key, value));
+        node.left = left;
+        node.right = right;
         node.update_height();
-        Self::balance(node)
+        Some(node)
+    }
+
+    // Splits a subtree into the entries below and above `key`, plus the entry equal to it.
+    // Each level joins the subtree on the far side back, and the join costs telescope to
+    // O(log n) overall.
+    fn split_rec<Q>(node: Link<K, V>, key: &Q) -> Split<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let Some(node) = node else {
+            return (None, None, None);
+        };
+
+        let Node {
+            key: node_key,
+            value,
+            left,
+            right,
+            ..
+        } = *node;
+        match key.cmp(node_key.borrow()) {
+            std::cmp::Ordering::Less => {
+                let (below, found, above) = Self::split_rec(left, key);
+                (below, found, Self::join_rec(above, node_key, value, right))
+            }
+            std::cmp::Ordering::Greater => {
+                let (below, found, above) = Self::split_rec(right, key);
+                (Self::join_rec(left, node_key, value, below), found, above)
+            }
+            std::cmp::Ordering::Equal => (left, Some((node_key, value)), right),
+        }
+    }
+
+    // Merges two subtrees, keeping the entries of `other` on duplicate keys
+    fn union(tree: Link<K, V>, other: Link<K, V>) -> Link<K, V> {
+        let Some(other) = other else {
+            return tree;
+        };
+        if tree.is_none() {
+            return Some(other);
+        }
+
+        let Node {
+            key,
+            value,
+            left,
+            right,
+            ..
+        } = *other;
+        let (below, _, above) = Self::split_rec(tree, &key);
+        Self::join_rec(
+            Self::union(below, left),
+            key,
+            value,
+            Self::union(above, right),
+        )
     }
 
     fn balance(mut node: Box<Node<K, V>>) -> Link<K, V> {
@@ -143,3 +618,1327 @@ impl<K: Ord + Copy, V: Copy> AvlTree<K, V> {
         }
     }
 }
+
+impl<K: Ord, V> AvlTree<K, V> {
+    /// Returns the number of entries in the tree.
+    pub fn len(&self) -> usize {
+        Node::size(&self.root)
+    }
+
+    /// Returns true if the tree contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Returns the entry with the `index`-th smallest key, counting from zero.
+    pub fn select(&self, index: usize) -> Option<(&K, &V)> {
+        let mut index = index;
+        let mut current = &self.root;
+        while let Some(node) = current {
+            let left_size = Node::size(&node.left);
+            match index.cmp(&left_size) {
+                std::cmp::Ordering::Less => current = &node.left,
+                std::cmp::Ordering::Equal => return Some((&node.key, &node.value)),
+                std::cmp::Ordering::Greater => {
+                    index -= left_size + 1;
+                    current = &node.right;
+                }
+            }
+        }
+        None
+    }
+
+    /// Checks the structural invariants of the tree: keys are in BST order, every node has a
+    /// balance factor of -1, 0 or 1, and the cached heights and subtree sizes are correct.
+    ///
+    /// Returns statistics about the tree when all invariants hold.
+    pub fn validate(&self) -> Result<TreeStats, InvariantViolation> {
+        let mut stats = TreeStats {
+            len: 0,
+            height: 0,
+            balance_factors: [0; 3],
+        };
+        stats.height = Self::validate_rec(&self.root, None, None, &mut stats)?;
+        Ok(stats)
+    }
+
+    // Returns the actual height of the subtree, with `stats.len` counting the nodes visited so
+    // far in order
+    fn validate_rec(
+        node: &Link<K, V>,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        stats: &mut TreeStats,
+    ) -> Result<i32, InvariantViolation> {
+        let Some(node) = node else {
+            return Ok(0);
+        };
+
+        let start = stats.len;
+        let left_height = Self::validate_rec(&node.left, lower, Some(&node.key), stats)?;
+
+        let position = stats.len;
+        let ordered = lower.is_none_or(|lower| *lower < node.key)
+            && upper.is_none_or(|upper| node.key < *upper);
+        if !ordered {
+            return Err(InvariantViolation::Unordered { position });
+        }
+        stats.len += 1;
+
+        let right_height = Self::validate_rec(&node.right, Some(&node.key), upper, stats)?;
+
+        let height = 1 + max(left_height, right_height);
+        if node.height != height {
+            return Err(InvariantViolation::StaleHeight {
+                position,
+                cached: node.height,
+                actual: height,
+            });
+        }
+
+        let size = stats.len - start;
+        if node.size != size {
+            return Err(InvariantViolation::StaleSize {
+                position,
+                cached: node.size,
+                actual: size,
+            });
+        }
+
+        let balance_factor = left_height - right_height;
+        if balance_factor.abs() > 1 {
+            return Err(InvariantViolation::Unbalanced {
+                position,
+                balance_factor,
+            });
+        }
+        stats.balance_factors[(balance_factor + 1) as usize] += 1;
+
+        Ok(height)
+    }
+
+    // Mutable counterpart of `select` returning only the value
+    fn select_value_mut(&mut self, index: usize) -> Option<&mut V> {
+        let mut index = index;
+        let mut current = &mut self.root;
+        while let Some(node) = current {
+            let left_size = Node::size(&node.left);
+            match index.cmp(&left_size) {
+                std::cmp::Ordering::Less => current = &mut node.left,
+                std::cmp::Ordering::Equal => return Some(&mut node.value),
+                std::cmp::Ordering::Greater => {
+                    index -= left_size + 1;
+                    current = &mut node.right;
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the entry with the smallest key.
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        let mut node = self.root.as_deref()?;
+        while let Some(left) = node.left.as_deref() {
+            node = left;
+        }
+        Some((&node.key, &node.value))
+    }
+
+    /// Returns the entry with the largest key.
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        let mut node = self.root.as_deref()?;
+        while let Some(right) = node.right.as_deref() {
+            node = right;
+        }
+        Some((&node.key, &node.value))
+    }
+
+    /// Returns the entry with the greatest key less than or equal to `key`.
+    pub fn floor<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.below(key, true)
+    }
+
+    /// Returns the entry with the smallest key greater than or equal to `key`.
+    pub fn ceiling<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.above(key, true)
+    }
+
+    /// Returns the entry with the greatest key strictly less than `key`.
+    pub fn predecessor<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.below(key, false)
+    }
+
+    /// Returns the entry with the smallest key strictly greater than `key`.
+    pub fn successor<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.above(key, false)
+    }
+
+    // Closest entry below `key`, remembering the last node we went right at
+    fn below<Q>(&self, key: &Q, inclusive: bool) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut best = None;
+        let mut current = &self.root;
+        while let Some(node) = current {
+            match key.cmp(node.key.borrow()) {
+                std::cmp::Ordering::Equal if inclusive => return Some((&node.key, &node.value)),
+                std::cmp::Ordering::Less | std::cmp::Ordering::Equal => current = &node.left,
+                std::cmp::Ordering::Greater => {
+                    best = Some((&node.key, &node.value));
+                    current = &node.right;
+                }
+            }
+        }
+        best
+    }
+
+    // Closest entry above `key`, remembering the last node we went left at
+    fn above<Q>(&self, key: &Q, inclusive: bool) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut best = None;
+        let mut current = &self.root;
+        while let Some(node) = current {
+            match key.cmp(node.key.borrow()) {
+                std::cmp::Ordering::Equal if inclusive => return Some((&node.key, &node.value)),
+                std::cmp::Ordering::Greater | std::cmp::Ordering::Equal => current = &node.right,
+                std::cmp::Ordering::Less => {
+                    best = Some((&node.key, &node.value));
+                    current = &node.left;
+                }
+            }
+        }
+        best
+    }
+
+    /// Returns the number of keys in the tree that are strictly less than `key`.
+    pub fn rank<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut rank = 0;
+        let mut current = &self.root;
+        while let Some(node) = current {
+            match key.cmp(node.key.borrow()) {
+                std::cmp::Ordering::Less => current = &node.left,
+                std::cmp::Ordering::Equal => return rank + Node::size(&node.left),
+                std::cmp::Ordering::Greater => {
+                    rank += Node::size(&node.left) + 1;
+                    current = &node.right;
+                }
+            }
+        }
+        rank
+    }
+
+    /// Iterates over the entries of the tree in ascending key order.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            walk: Walk::new(self.root.as_deref()),
+        }
+    }
+
+    /// Iterates over the entries of the tree in ascending key order, with mutable values.
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        // Values may be rewritten through the iterator
+        self.version = next_version();
+        IterMut {
+            walk: Walk::new(unsafe { NodeMut::from_link(&raw mut self.root) }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Iterates over the keys of the tree in ascending order.
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    /// Iterates over the values of the tree in ascending key order.
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// Iterates over the entries whose keys fall within `range`, in ascending key order.
+    ///
+    /// Panics if the start of the range is greater than its end, or if both bounds are
+    /// excluded and equal, like `BTreeMap::range`.
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        Range {
+            walk: Walk::range(self.root.as_deref(), range),
+        }
+    }
+
+    /// Iterates over the entries whose keys fall within `range`, with mutable values.
+    pub fn range_mut<Q, R>(&mut self, range: R) -> RangeMut<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        self.version = next_version();
+        RangeMut {
+            walk: Walk::range(unsafe { NodeMut::from_link(&raw mut self.root) }, range),
+            _marker: PhantomData,
+        }
+    }
+}
+
+// A handle to a node that the in-order walk can step through.
+trait NodeRef: Copy {
+    type Key: Ord;
+
+    fn key(&self) -> &Self::Key;
+    fn left(self) -> Option<Self>;
+    fn right(self) -> Option<Self>;
+    fn same(self, other: Self) -> bool;
+}
+
+impl<K: Ord, V> NodeRef for &Node<K, V> {
+    type Key = K;
+
+    fn key(&self) -> &K {
+        &self.key
+    }
+
+    fn left(self) -> Option<Self> {
+        self.left.as_deref()
+    }
+
+    fn right(self) -> Option<Self> {
+        self.right.as_deref()
+    }
+
+    fn same(self, other: Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// Mutable iterators hand out `&mut V` for nodes that are still reachable from the other end
+// of the walk, so they track nodes through raw pointers derived from the exclusive borrow.
+struct NodeMut<K: Ord, V>(NonNull<Node<K, V>>);
+
+impl<K: Ord, V> NodeMut<K, V> {
+    // Returns the node `link` points to, read as the raw pointer its `Box` holds: borrowing the
+    // box or the node would invalidate the values already handed out below it.
+    //
+    // Safety: `link` must point to a live link of the tree.
+    unsafe fn from_link(link: *mut Link<K, V>) -> Option<Self> {
+        // `Option<Box<T>>` is guaranteed to have the layout of a nullable pointer to `T`
+        unsafe { link.cast::<Option<NonNull<Node<K, V>>>>().read() }.map(NodeMut)
+    }
+
+    // Safety: the node must be alive and no other reference to its value may exist.
+    unsafe fn entry<'a>(self) -> (&'a K, &'a mut V) {
+        let node = self.0.as_ptr();
+        unsafe { (&(*node).key, &mut (*node).value) }
+    }
+}
+
+impl<K: Ord, V> Clone for NodeMut<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K: Ord, V> Copy for NodeMut<K, V> {}
+
+impl<K: Ord, V> NodeRef for NodeMut<K, V> {
+    type Key = K;
+
+    fn key(&self) -> &K {
+        unsafe { &(*self.0.as_ptr()).key }
+    }
+
+    fn left(self) -> Option<Self> {
+        unsafe { NodeMut::from_link(&raw mut (*self.0.as_ptr()).left) }
+    }
+
+    fn right(self) -> Option<Self> {
+        unsafe { NodeMut::from_link(&raw mut (*self.0.as_ptr()).right) }
+    }
+
+    fn same(self, other: Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+/// In-order walk over a subtree driven by two explicit stacks, one per end.
+///
+/// The top of each stack is the next node to yield from that end, and the walk is exhausted
+/// once both ends meet on the same node.
+struct Walk<N> {
+    front: Vec<N>,
+    back: Vec<N>,
+}
+
+impl<N: NodeRef> Walk<N> {
+    fn new(root: Option<N>) -> Self {
+        let mut walk = Walk {
+            front: Vec::new(),
+            back: Vec::new(),
+        };
+        walk.push_left(root);
+        walk.push_right(root);
+        walk
+    }
+
+    fn range<Q, R>(root: Option<N>, range: R) -> Self
+    where
+        N::Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        match (range.start_bound(), range.end_bound()) {
+            (Bound::Excluded(start), Bound::Excluded(end)) if start == end => {
+                panic!("range start and end are equal and excluded in AvlTree")
+            }
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) if start > end => panic!("range start is greater than range end in AvlTree"),
+            _ => {}
+        }
+
+        let mut walk = Walk {
+            front: Vec::new(),
+            back: Vec::new(),
+        };
+
+        // Descend towards the first key past the lower bound, stacking the nodes we went left at
+        let mut current = root;
+        while let Some(node) = current {
+            if above_lower(node.key().borrow(), range.start_bound()) {
+                walk.front.push(node);
+                current = node.left();
+            } else {
+                current = node.right();
+            }
+        }
+
+        // Likewise towards the last key before the upper bound
+        let mut current = root;
+        while let Some(node) = current {
+            if below_upper(node.key().borrow(), range.end_bound()) {
+                walk.back.push(node);
+                current = node.right();
+            } else {
+                current = node.left();
+            }
+        }
+
+        let empty = match (walk.front.last(), walk.back.last()) {
+            (Some(first), Some(last)) => first.key() > last.key(),
+            _ => true,
+        };
+        if empty {
+            walk.front.clear();
+            walk.back.clear();
+        }
+        walk
+    }
+
+    fn push_left(&mut self, mut node: Option<N>) {
+        while let Some(n) = node {
+            self.front.push(n);
+            node = n.left();
+        }
+    }
+
+    fn push_right(&mut self, mut node: Option<N>) {
+        while let Some(n) = node {
+            self.back.push(n);
+            node = n.right();
+        }
+    }
+
+    fn next(&mut self) -> Option<N> {
+        let node = self.front.pop()?;
+        if self.back.last().is_some_and(|last| last.same(node)) {
+            self.front.clear();
+            self.back.clear();
+        } else {
+            self.push_left(node.right());
+        }
+        Some(node)
+    }
+
+    fn next_back(&mut self) -> Option<N> {
+        let node = self.back.pop()?;
+        if self.front.last().is_some_and(|first| first.same(node)) {
+            self.front.clear();
+            self.back.clear();
+        } else {
+            self.push_right(node.left());
+        }
+        Some(node)
+    }
+}
+
+fn above_lower<Q: Ord + ?Sized>(key: &Q, bound: Bound<&Q>) -> bool {
+    match bound {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+fn below_upper<Q: Ord + ?Sized>(key: &Q, bound: Bound<&Q>) -> bool {
+    match bound {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+/// Iterator over the entries of an `AvlTree` in ascending key order.
+pub struct Iter<'a, K: Ord, V> {
+    walk: Walk<&'a Node<K, V>>,
+}
+
+impl<'a, K: Ord, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walk.next().map(|node| (&node.key, &node.value))
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for Iter<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.walk.next_back().map(|node| (&node.key, &node.value))
+    }
+}
+
+/// Iterator over the entries of an `AvlTree` in ascending key order, with mutable values.
+pub struct IterMut<'a, K: Ord, V> {
+    walk: Walk<NodeMut<K, V>>,
+    _marker: PhantomData<&'a mut Node<K, V>>,
+}
+
+impl<'a, K: Ord, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Each node is yielded at most once, so its value is never borrowed twice
+        self.walk.next().map(|node| unsafe { node.entry() })
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for IterMut<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.walk.next_back().map(|node| unsafe { node.entry() })
+    }
+}
+
+/// Iterator over the keys of an `AvlTree` in ascending order.
+pub struct Keys<'a, K: Ord, V> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K: Ord, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, _)| key)
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for Keys<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(key, _)| key)
+    }
+}
+
+/// Iterator over the values of an `AvlTree` in ascending key order.
+pub struct Values<'a, K: Ord, V> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K: Ord, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, value)| value)
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for Values<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(_, value)| value)
+    }
+}
+
+/// Iterator over a sub-range of the entries of an `AvlTree` in ascending key order.
+pub struct Range<'a, K: Ord, V> {
+    walk: Walk<&'a Node<K, V>>,
+}
+
+impl<'a, K: Ord, V> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walk.next().map(|node| (&node.key, &node.value))
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for Range<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.walk.next_back().map(|node| (&node.key, &node.value))
+    }
+}
+
+/// Iterator over a sub-range of the entries of an `AvlTree`, with mutable values.
+pub struct RangeMut<'a, K: Ord, V> {
+    walk: Walk<NodeMut<K, V>>,
+    _marker: PhantomData<&'a mut Node<K, V>>,
+}
+
+impl<'a, K: Ord, V> Iterator for RangeMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walk.next().map(|node| unsafe { node.entry() })
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for RangeMut<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.walk.next_back().map(|node| unsafe { node.entry() })
+    }
+}
+
+impl<'a, K: Ord, V> IntoIterator for &'a AvlTree<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, K: Ord, V> IntoIterator for &'a mut AvlTree<K, V> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl<K: Ord, V> FromIterator<(K, V)> for AvlTree<K, V> {
+    /// Bulk-builds the tree when the entries come sorted by key, and falls back to inserting
+    /// them one by one otherwise.
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let entries: Vec<(K, V)> = iter.into_iter().collect();
+        if entries.is_sorted_by(|a, b| a.0 <= b.0) {
+            return Self::from_sorted_vec(entries);
+        }
+
+        let mut tree = AvlTree::new();
+        for (key, value) in entries {
+            tree.insert(key, value);
+        }
+        tree
+    }
+}
+
+impl<K: Ord, V> Extend<(K, V)> for AvlTree<K, V> {
+    /// Bulk-builds the tree when it is empty, see `FromIterator`, and inserts the entries one by
+    /// one otherwise.
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        if self.is_empty() {
+            *self = iter.into_iter().collect();
+            return;
+        }
+
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+/// Statistics gathered by `AvlTree::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Number of nodes in the tree.
+    pub len: usize,
+    /// Height of the tree, zero when empty.
+    pub height: i32,
+    /// Number of nodes with a balance factor of -1, 0 and 1 respectively.
+    pub balance_factors: [usize; 3],
+}
+
+/// A broken structural invariant reported by `AvlTree::validate`.
+///
+/// `position` is the in-order index of the offending node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// The node's key is not within the bounds set by its ancestors.
+    Unordered { position: usize },
+    /// The heights of the node's subtrees differ by more than one.
+    Unbalanced {
+        position: usize,
+        balance_factor: i32,
+    },
+    /// The cached height of the node does not match its subtrees.
+    StaleHeight {
+        position: usize,
+        cached: i32,
+        actual: i32,
+    },
+    /// The cached subtree size of the node does not match its subtrees.
+    StaleSize {
+        position: usize,
+        cached: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvariantViolation::Unordered { position } => {
+                write!(f, "node {position} is out of BST order")
+            }
+            InvariantViolation::Unbalanced {
+                position,
+                balance_factor,
+            } => write!(f, "node {position} has balance factor {balance_factor}"),
+            InvariantViolation::StaleHeight {
+                position,
+                cached,
+                actual,
+            } => write!(
+                f,
+                "node {position} caches height {cached} but has height {actual}"
+            ),
+            InvariantViolation::StaleSize {
+                position,
+                cached,
+                actual,
+            } => write!(
+                f,
+                "node {position} caches size {cached} but has size {actual}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
+
+/// A view into a single entry of an `AvlTree`, obtained from `AvlTree::entry`.
+pub enum Entry<'a, K: Ord, V> {
+    Vacant(VacantEntry<'a, K, V>),
+    Occupied(OccupiedEntry<'a, K, V>),
+}
+
+/// A vacant entry of an `AvlTree`.
+pub struct VacantEntry<'a, K: Ord, V> {
+    key: K,
+    // Number of keys below `key`, which becomes the in-order position of the inserted entry
+    rank: usize,
+    tree: &'a mut AvlTree<K, V>,
+}
+
+/// An occupied entry of an `AvlTree`.
+pub struct OccupiedEntry<'a, K: Ord, V> {
+    // The key the entry was looked up with, equal to the stored one
+    key: K,
+    // The entry holds the only borrow of the tree, so the node cannot move or be freed
+    node: NonNull<Node<K, V>>,
+    tree: &'a mut AvlTree<K, V>,
+}
+
+impl<'a, K: Ord, V> Entry<'a, K, V> {
+    /// Returns the key of this entry.
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Vacant(entry) => entry.key(),
+            Entry::Occupied(entry) => entry.key(),
+        }
+    }
+
+    /// Inserts `default` if the entry is vacant and returns a mutable reference to the value.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Vacant(entry) => entry.insert(default),
+            Entry::Occupied(entry) => entry.into_mut(),
+        }
+    }
+
+    /// Inserts the result of `default` if the entry is vacant and returns a mutable reference
+    /// to the value.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Vacant(entry) => entry.insert(default()),
+            Entry::Occupied(entry) => entry.into_mut(),
+        }
+    }
+
+    /// Like `or_insert_with`, but the closure receives the key.
+    pub fn or_insert_with_key<F: FnOnce(&K) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Vacant(entry) => {
+                let value = default(entry.key());
+                entry.insert(value)
+            }
+            Entry::Occupied(entry) => entry.into_mut(),
+        }
+    }
+
+    /// Applies `f` to the value if the entry is occupied.
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+        }
+    }
+}
+
+impl<'a, K: Ord, V: Default> Entry<'a, K, V> {
+    /// Inserts the default value if the entry is vacant and returns a mutable reference to
+    /// the value.
+    pub fn or_default(self) -> &'a mut V {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<'a, K: Ord, V> VacantEntry<'a, K, V> {
+    /// Returns the key that would be used when inserting through this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Takes ownership of the key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Inserts `value` under the entry's key and returns a mutable reference to it.
+    pub fn insert(self, value: V) -> &'a mut V {
+        let tree = self.tree;
+        tree.insert(self.key, value);
+        tree.select_value_mut(self.rank).unwrap()
+    }
+}
+
+impl<'a, K: Ord, V> OccupiedEntry<'a, K, V> {
+    /// Returns the key stored in the tree.
+    pub fn key(&self) -> &K {
+        unsafe { &self.node.as_ref().key }
+    }
+
+    /// Returns a reference to the value.
+    pub fn get(&self) -> &V {
+        unsafe { &self.node.as_ref().value }
+    }
+
+    /// Returns a mutable reference to the value.
+    pub fn get_mut(&mut self) -> &mut V {
+        unsafe { &mut self.node.as_mut().value }
+    }
+
+    /// Converts the entry into a mutable reference to the value with the tree's lifetime.
+    pub fn into_mut(mut self) -> &'a mut V {
+        unsafe { &mut self.node.as_mut().value }
+    }
+
+    /// Replaces the value, returning the previous one.
+    pub fn insert(&mut self, value: V) -> V {
+        std::mem::replace(self.get_mut(), value)
+    }
+
+    /// Removes the entry from the tree, returning its value.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Removes the entry from the tree, returning the stored key and value.
+    pub fn remove_entry(self) -> (K, V) {
+        self.tree.remove_entry(&self.key).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_remove_rebalances() {
+        let mut tree = AvlTree::new();
+        let mut rng = StdRng::seed_from_u64(4242);
+        let mut keys: Vec<i32> = (0..2000).collect();
+        keys.shuffle(&mut rng);
+        for &key in &keys {
+            tree.insert(key, key * 2);
+            tree.validate().unwrap();
+        }
+
+        keys.shuffle(&mut rng);
+        let (removed, kept) = keys.split_at(1000);
+        for &key in removed {
+            assert_eq!(tree.remove(&key), Some(key * 2));
+            assert_eq!(tree.remove(&key), None);
+            tree.validate().unwrap();
+        }
+        for &key in removed {
+            assert_eq!(tree.lookup(&key), None);
+        }
+        for &key in kept {
+            assert_eq!(tree.lookup(&key), Some(key * 2));
+        }
+    }
+
+    #[test]
+    fn test_pop_first_and_last() {
+        let mut tree = AvlTree::new();
+        for key in 0..100 {
+            tree.insert(key, key);
+            tree.validate().unwrap();
+        }
+
+        for expected in 0..50 {
+            assert_eq!(tree.pop_first(), Some((expected, expected)));
+            assert_eq!(tree.pop_last(), Some((99 - expected, 99 - expected)));
+            tree.validate().unwrap();
+        }
+        assert_eq!(tree.pop_first(), None);
+        assert_eq!(tree.pop_last(), None);
+        assert!(tree.root.is_none());
+    }
+
+    #[test]
+    fn test_version_changes_on_removal() {
+        let mut tree = AvlTree::new();
+        tree.insert(1, 1);
+        let compiled_at = tree.version();
+
+        assert_eq!(tree.remove(&2), None);
+        assert_eq!(tree.version(), compiled_at);
+
+        assert_eq!(tree.remove(&1), Some(1));
+        assert_ne!(tree.version(), compiled_at);
+    }
+
+    #[test]
+    fn test_iter_in_order_from_both_ends() {
+        let mut tree = AvlTree::new();
+        let mut rng = StdRng::seed_from_u64(777);
+        let mut keys: Vec<i32> = (0..500).collect();
+        keys.shuffle(&mut rng);
+        for &key in &keys {
+            tree.insert(key, key + 1);
+            tree.validate().unwrap();
+        }
+
+        let forward: Vec<i32> = tree.keys().copied().collect();
+        assert_eq!(forward, (0..500).collect::<Vec<_>>());
+        let backward: Vec<i32> = tree.values().rev().copied().collect();
+        assert_eq!(backward, (1..501).rev().collect::<Vec<_>>());
+
+        // Alternating ends must meet exactly once in the middle
+        let mut iter = tree.iter();
+        let mut seen = Vec::new();
+        loop {
+            match (iter.next(), iter.next_back()) {
+                (Some((&a, _)), Some((&b, _))) => seen.extend([a, b]),
+                (Some((&a, _)), None) => seen.push(a),
+                (None, _) => break,
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, (0..500).collect::<Vec<_>>());
+
+        for (_, value) in tree.iter_mut() {
+            *value *= 2;
+        }
+        assert!(tree.iter().all(|(&k, &v)| v == (k + 1) * 2));
+    }
+
+    // Run under Miri (`cargo +nightly miri test avl::tests::test_iter_mut_from_both_ends`) to
+    // check that the values handed out from either end stay valid while the walk goes on
+    #[test]
+    fn test_iter_mut_from_both_ends() {
+        let mut tree: AvlTree<i32, i32> = (0..64).map(|k| (k, k)).collect();
+        let mut values = Vec::new();
+        let mut iter = tree.iter_mut();
+        loop {
+            match (iter.next(), iter.next_back()) {
+                (Some((_, a)), Some((_, b))) => values.extend([a, b]),
+                (Some((_, a)), None) => values.push(a),
+                (None, _) => break,
+            }
+        }
+        for value in &mut values {
+            **value += 100;
+        }
+        assert_eq!(values.len(), 64);
+        assert!(tree.iter().all(|(&k, &v)| v == k + 100));
+
+        let mut values = Vec::new();
+        let mut range = tree.range_mut(10..50);
+        while let Some((_, a)) = range.next_back() {
+            values.push(a);
+            if let Some((_, b)) = range.next() {
+                values.push(b);
+            }
+        }
+        for value in values {
+            *value = -*value;
+        }
+        assert!(tree.range(10..50).all(|(&k, &v)| v == -(k + 100)));
+        assert_eq!(tree.lookup(&9), Some(109));
+        assert_eq!(tree.lookup(&50), Some(150));
+    }
+
+    #[test]
+    fn test_range_matches_btreemap() {
+        let mut tree = AvlTree::new();
+        let mut reference = BTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(31337);
+        for _ in 0..300 {
+            let key = rng.random_range(0..1000);
+            tree.insert(key, key);
+            reference.insert(key, key);
+            tree.validate().unwrap();
+        }
+
+        for _ in 0..200 {
+            let a = rng.random_range(-10..1010);
+            let b = rng.random_range(a..1010);
+            let expected: Vec<_> = reference.range(a..b).collect();
+            assert_eq!(tree.range(a..b).collect::<Vec<_>>(), expected);
+            let expected: Vec<_> = reference.range(a..=b).rev().collect();
+            assert_eq!(tree.range(a..=b).rev().collect::<Vec<_>>(), expected);
+            let expected: Vec<_> = reference.range(..b).collect();
+            assert_eq!(tree.range(..b).collect::<Vec<_>>(), expected);
+            let bounds = (Bound::Excluded(a), Bound::Unbounded);
+            let expected: Vec<_> = reference.range(bounds).collect();
+            assert_eq!(tree.range(bounds).collect::<Vec<_>>(), expected);
+        }
+
+        for (_, value) in tree.range_mut(100..200) {
+            *value = -1;
+        }
+        assert!(
+            tree.iter()
+                .all(|(k, &v)| (v == -1) == (100..200).contains(k))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "range start is greater than range end")]
+    fn test_range_panics_on_inverted_bounds() {
+        let mut tree = AvlTree::new();
+        tree.insert(1, 1);
+        let _ = tree.range((Bound::Included(5), Bound::Excluded(1)));
+    }
+
+    #[test]
+    fn test_rank_and_select() {
+        let mut tree = AvlTree::new();
+        let mut rng = StdRng::seed_from_u64(2024);
+        let mut keys: Vec<i32> = (0..1000).map(|k| k * 3).collect();
+        keys.shuffle(&mut rng);
+        for &key in &keys {
+            tree.insert(key, -key);
+            tree.validate().unwrap();
+        }
+        for &key in &keys[..300] {
+            tree.remove(&key);
+            tree.validate().unwrap();
+        }
+
+        let mut sorted = keys[300..].to_vec();
+        sorted.sort();
+        assert_eq!(tree.len(), sorted.len());
+        for (index, key) in sorted.iter().enumerate() {
+            assert_eq!(tree.select(index), Some((key, &-key)));
+            assert_eq!(tree.rank(key), index);
+            // Keys that are absent rank just like the next present key
+            assert_eq!(
+                tree.rank(&(key - 1)),
+                sorted.partition_point(|k| *k < key - 1)
+            );
+        }
+        assert_eq!(tree.select(sorted.len()), None);
+        assert_eq!(tree.rank(&i32::MAX), sorted.len());
+    }
+
+    #[test]
+    fn test_owned_keys_and_values() {
+        let mut tree: AvlTree<String, Vec<u8>> = AvlTree::new();
+        for word in ["pear", "apple", "fig", "banana", "cherry"] {
+            assert_eq!(
+                tree.insert(word.to_string(), word.as_bytes().to_vec()),
+                None
+            );
+        }
+        assert_eq!(
+            tree.insert("fig".to_string(), b"FIG".to_vec()),
+            Some(b"fig".to_vec())
+        );
+
+        // Borrowed lookups do not need an owned `String`
+        assert_eq!(tree.get("fig").map(Vec::as_slice), Some(&b"FIG"[..]));
+        assert!(tree.contains_key("apple"));
+        assert!(!tree.contains_key("grape"));
+        tree.get_mut("pear").unwrap().push(b'!');
+        assert_eq!(tree.get("pear").map(Vec::as_slice), Some(&b"pear!"[..]));
+
+        assert_eq!(tree.remove("banana"), Some(b"banana".to_vec()));
+        tree.validate().unwrap();
+        let keys: Vec<&str> = tree.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["apple", "cherry", "fig", "pear"]);
+        let keys: Vec<&str> = tree
+            .range::<str, _>((Bound::Included("b"), Bound::Excluded("g")))
+            .map(|(k, _)| k.as_str())
+            .collect();
+        assert_eq!(keys, ["cherry", "fig"]);
+        tree.validate().unwrap();
+    }
+
+    #[test]
+    fn test_entry_api() {
+        let mut counts: AvlTree<String, usize> = AvlTree::new();
+        let text = "the quick brown fox jumps over the lazy dog the end";
+        for word in text.split(' ') {
+            *counts.entry(word.to_string()).or_insert(0) += 1;
+            counts.validate().unwrap();
+        }
+        assert_eq!(counts.get("the"), Some(&3));
+        assert_eq!(counts.get("fox"), Some(&1));
+        assert_eq!(counts.len(), 9);
+
+        counts.entry("fox".to_string()).and_modify(|c| *c += 10);
+        assert_eq!(counts.get("fox"), Some(&11));
+        assert_eq!(*counts.entry("cat".to_string()).or_default(), 0);
+
+        match counts.entry("lazy".to_string()) {
+            Entry::Occupied(entry) => {
+                assert_eq!(entry.key(), "lazy");
+                assert_eq!(entry.remove_entry(), ("lazy".to_string(), 1));
+            }
+            Entry::Vacant(_) => panic!("expected an occupied entry"),
+        }
+        assert!(!counts.contains_key("lazy"));
+        counts.validate().unwrap();
+
+        match counts.entry("zebra".to_string()) {
+            Entry::Vacant(entry) => assert_eq!(*entry.insert(7), 7),
+            Entry::Occupied(_) => panic!("expected a vacant entry"),
+        }
+        assert_eq!(counts.pop_last(), Some(("zebra".to_string(), 7)));
+    }
+
+    #[test]
+    fn test_validate_reports_stats_and_violations() {
+        let mut tree = AvlTree::new();
+        assert_eq!(tree.validate().unwrap().height, 0);
+        for key in 0..7 {
+            tree.insert(key, key);
+        }
+        // Sequential inserts into an AVL tree end up perfectly balanced at 2^k - 1 nodes
+        let stats = tree.validate().unwrap();
+        assert_eq!(stats.len, 7);
+        assert_eq!(stats.height, 3);
+        assert_eq!(stats.balance_factors, [0, 7, 0]);
+
+        tree.root.as_mut().unwrap().height = 5;
+        assert_eq!(
+            tree.validate(),
+            Err(InvariantViolation::StaleHeight {
+                position: 3,
+                cached: 5,
+                actual: 3
+            })
+        );
+        tree.root.as_mut().unwrap().height = 3;
+
+        tree.root.as_mut().unwrap().left.as_mut().unwrap().key = 10;
+        assert_eq!(
+            tree.validate(),
+            Err(InvariantViolation::Unordered { position: 1 })
+        );
+    }
+
+    #[test]
+    fn test_from_sorted_builds_minimum_height() {
+        for len in [0usize, 1, 2, 3, 7, 8, 1000, 4095, 4096] {
+            let tree = AvlTree::from_sorted_iter((0..len).map(|k| (k, k * 10)));
+            let stats = tree.validate().unwrap();
+            assert_eq!(stats.len, len);
+            assert_eq!(stats.height as u32, usize::BITS - len.leading_zeros());
+            // The middle entry ends up at the root
+            assert_eq!(
+                tree.root().map(|node| node.key),
+                (len > 0).then_some(len / 2)
+            );
+            assert!(
+                tree.iter()
+                    .map(|(&k, &v)| (k, v))
+                    .eq((0..len).map(|k| (k, k * 10)))
+            );
+        }
+
+        // Runs of equal keys keep the last value
+        let tree = AvlTree::from_sorted_vec(vec![(1, 'a'), (2, 'b'), (2, 'c'), (2, 'e'), (3, 'd')]);
+        tree.validate().unwrap();
+        assert_eq!(tree.values().collect::<String>(), "aed");
+    }
+
+    #[test]
+    #[should_panic(expected = "not sorted")]
+    fn test_from_sorted_rejects_unsorted_input() {
+        let _ = AvlTree::from_sorted_vec(vec![(2, ()), (1, ())]);
+    }
+
+    #[test]
+    fn test_collect_and_extend() {
+        let mut rng = StdRng::seed_from_u64(606);
+        let mut keys: Vec<i32> = (0..500).collect();
+        keys.shuffle(&mut rng);
+
+        let unsorted: AvlTree<i32, i32> = keys.iter().map(|&k| (k, k)).collect();
+        unsorted.validate().unwrap();
+        assert!(unsorted.keys().copied().eq(0..500));
+
+        let mut tree = AvlTree::new();
+        tree.extend((0..500).map(|k| (k, k)));
+        tree.validate().unwrap();
+        tree.extend(keys.iter().map(|&k| (k + 250, -k)));
+        tree.validate().unwrap();
+        assert_eq!(tree.len(), 750);
+        assert_eq!(tree.get(&300), Some(&-50));
+        assert_eq!(tree.get(&100), Some(&100));
+    }
+
+    #[test]
+    fn test_join_and_split() {
+        let mut rng = StdRng::seed_from_u64(707);
+        for _ in 0..50 {
+            // Trees of very different heights exercise the spine descent
+            let left_len = rng.random_range(0..300);
+            let right_len = rng.random_range(0..300);
+            let left: AvlTree<i32, i32> = (0..left_len).map(|k| (k, k)).collect();
+            let right: AvlTree<i32, i32> = (0..right_len)
+                .map(|k| (left_len + 1 + k, left_len + 1 + k))
+                .collect();
+
+            let tree = AvlTree::join(left, left_len, left_len, right);
+            tree.validate().unwrap();
+            let len = left_len + right_len + 1;
+            assert!(tree.keys().copied().eq(0..len));
+
+            let pivot = rng.random_range(-5..len + 5);
+            let (below, found, above) = tree.split(&pivot);
+            below.validate().unwrap();
+            above.validate().unwrap();
+            assert_eq!(found, (0..len).contains(&pivot).then_some(pivot));
+            assert!(below.keys().copied().eq(0..pivot.clamp(0, len)));
+            assert!(above.keys().copied().eq((pivot + 1).clamp(0, len)..len));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "join keys of the left tree")]
+    fn test_join_rejects_overlapping_trees() {
+        let left: AvlTree<i32, ()> = (0..10).map(|k| (k, ())).collect();
+        let _ = AvlTree::join(left, 5, (), AvlTree::new());
+    }
+
+    #[test]
+    fn test_append_and_split_off_match_btreemap() {
+        let mut rng = StdRng::seed_from_u64(717);
+        for _ in 0..30 {
+            let mut tree = AvlTree::new();
+            let mut other = AvlTree::new();
+            let mut reference = BTreeMap::new();
+            let mut other_reference = BTreeMap::new();
+            for _ in 0..rng.random_range(0..200) {
+                let key = rng.random_range(0..400);
+                tree.insert(key, 0);
+                reference.insert(key, 0);
+            }
+            for _ in 0..rng.random_range(0..200) {
+                let key = rng.random_range(200..600);
+                other.insert(key, 1);
+                other_reference.insert(key, 1);
+            }
+
+            tree.append(&mut other);
+            reference.append(&mut other_reference);
+            tree.validate().unwrap();
+            other.validate().unwrap();
+            assert!(other.is_empty());
+            assert!(tree.iter().eq(reference.iter()));
+
+            let at = rng.random_range(-10..610);
+            let tail = tree.split_off(&at);
+            let reference_tail = reference.split_off(&at);
+            tree.validate().unwrap();
+            tail.validate().unwrap();
+            assert!(tree.iter().eq(reference.iter()));
+            assert!(tail.iter().eq(reference_tail.iter()));
+        }
+    }
+
+    #[test]
+    fn test_neighbor_queries_match_btreemap() {
+        let mut rng = StdRng::seed_from_u64(808);
+        let mut tree = AvlTree::new();
+        let mut reference = BTreeMap::new();
+        assert_eq!(tree.first_key_value(), None);
+        assert_eq!(tree.floor(&0), None);
+        for _ in 0..300 {
+            let key = rng.random_range(0..1000);
+            tree.insert(key, key * 2);
+            reference.insert(key, key * 2);
+            tree.validate().unwrap();
+        }
+
+        assert_eq!(tree.first_key_value(), reference.first_key_value());
+        assert_eq!(tree.last_key_value(), reference.last_key_value());
+        for probe in -2..1002 {
+            assert_eq!(tree.floor(&probe), reference.range(..=probe).next_back());
+            assert_eq!(tree.ceiling(&probe), reference.range(probe..).next());
+            assert_eq!(
+                tree.predecessor(&probe),
+                reference.range(..probe).next_back()
+            );
+            let bounds = (Bound::Excluded(probe), Bound::Unbounded);
+            assert_eq!(tree.successor(&probe), reference.range(bounds).next());
+        }
+    }
+}
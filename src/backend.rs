@@ -0,0 +1,331 @@
+use crate::avl::AvlTree;
+use crate::codegen::{self, KeyCodegen};
+use crate::compiled::{CompiledLookup, JitValue};
+use crate::jit_f64::OrdF64;
+use crate::{jit_avx, jit_sse};
+
+use std::fmt;
+
+/// The instruction sets compiled lookups can be generated for, from the least to the most
+/// demanding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Backend {
+    /// Baseline x86-64: general-purpose registers, and SSE2 where the key code uses vectors.
+    Scalar,
+    /// 128-bit SSE2 comparisons, see `jit_sse::compile_sse`.
+    Sse,
+    /// 256-bit AVX2 comparisons, see `jit_avx::compile_avx2`.
+    Avx2,
+    /// 512-bit AVX-512 comparisons, see `jit_avx::compile_avx512`.
+    Avx512,
+}
+
+impl Backend {
+    /// Returns the CPU features, beyond baseline x86-64, that the generated code uses.
+    pub fn required_features(self) -> &'static [&'static str] {
+        match self {
+            Backend::Scalar => &[],
+            Backend::Sse => &[],
+            Backend::Avx2 => &["avx", "avx2", "bmi1"],
+            Backend::Avx512 => &["avx512f", "avx512bw", "bmi1"],
+        }
+    }
+
+    /// Checks at runtime that the host CPU supports every feature the backend uses.
+    pub fn check(self) -> Result<(), BackendError> {
+        match self
+            .required_features()
+            .iter()
+            .find(|&&feature| !is_feature_detected(feature))
+        {
+            Some(&feature) => Err(BackendError::MissingFeature {
+                backend: self,
+                feature,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns true if the host CPU supports the backend.
+    pub fn is_supported(self) -> bool {
+        self.check().is_ok()
+    }
+
+    /// Panics unless the host CPU supports the backend, so that compilers never emit
+    /// instructions it lacks.
+    pub(crate) fn assert_supported(self) {
+        if let Err(err) = self.check() {
+            panic!("{err}");
+        }
+    }
+}
+
+// `is_x86_feature_detected!` only takes literals
+fn is_feature_detected(feature: &str) -> bool {
+    match feature {
+        "avx" => is_x86_feature_detected!("avx"),
+        "avx2" => is_x86_feature_detected!("avx2"),
+        "bmi1" => is_x86_feature_detected!("bmi1"),
+        "avx512f" => is_x86_feature_detected!("avx512f"),
+        "avx512bw" => is_x86_feature_detected!("avx512bw"),
+        _ => unreachable!("unknown CPU feature {feature}"),
+    }
+}
+
+/// The reason a backend cannot compile a lookup, reported by `compile_backend`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendError {
+    /// The host CPU lacks a feature the backend uses.
+    MissingFeature {
+        backend: Backend,
+        feature: &'static str,
+    },
+    /// The backend has no compiler for the key type.
+    UnsupportedKey { backend: Backend },
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::MissingFeature { backend, feature } => {
+                write!(
+                    f,
+                    "the {backend:?} backend needs {feature}, which the CPU lacks"
+                )
+            }
+            BackendError::UnsupportedKey { backend } => {
+                write!(f, "the {backend:?} backend does not support this key type")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+// Keeps the set of `BackendKey` types closed: `CompiledRefLookup` trusts `compile_for` to
+// compile the very tree it is given
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Keys whose lookups can be compiled by several backends.
+///
+/// The trait is sealed: it is implemented for the key types of this crate only.
+pub trait BackendKey: KeyCodegen + Sized + sealed::Sealed {
+    /// The backends with a compiler for the key type, from the most to the least preferred.
+    /// `Backend::Scalar` always comes last.
+    const BACKENDS: &'static [Backend];
+
+    /// Compiles the lookup for `tree` with `backend`, one of `BACKENDS`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the host CPU does not support `backend`.
+    fn compile_for<V: JitValue>(
+        tree: &AvlTree<Self, V>,
+        backend: Backend,
+    ) -> CompiledLookup<Self, V>;
+}
+
+macro_rules! impl_scalar_backend_key {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl sealed::Sealed for $ty {}
+
+            impl BackendKey for $ty {
+                const BACKENDS: &'static [Backend] = &[Backend::Scalar];
+
+                fn compile_for<V: JitValue>(
+                    tree: &AvlTree<Self, V>,
+                    _backend: Backend,
+                ) -> CompiledLookup<Self, V> {
+                    codegen::compile(tree)
+                }
+            }
+        )*
+    };
+}
+
+impl_scalar_backend_key!(
+    i8,
+    i16,
+    i32,
+    i64,
+    isize,
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    OrdF64,
+    Vec<u8>,
+    Box<[u8]>,
+);
+
+impl sealed::Sealed for [u8; 16] {}
+
+impl BackendKey for [u8; 16] {
+    const BACKENDS: &'static [Backend] = &[Backend::Sse, Backend::Scalar];
+
+    fn compile_for<V: JitValue>(
+        tree: &AvlTree<Self, V>,
+        backend: Backend,
+    ) -> CompiledLookup<Self, V> {
+        match backend {
+            Backend::Sse => jit_sse::compile_sse(tree),
+            _ => jit_sse::compile_scalar(tree),
+        }
+    }
+}
+
+impl sealed::Sealed for [u8; 32] {}
+
+impl BackendKey for [u8; 32] {
+    const BACKENDS: &'static [Backend] = &[Backend::Avx2, Backend::Scalar];
+
+    fn compile_for<V: JitValue>(
+        tree: &AvlTree<Self, V>,
+        backend: Backend,
+    ) -> CompiledLookup<Self, V> {
+        match backend {
+            Backend::Avx2 => jit_avx::compile_avx2(tree),
+            _ => codegen::compile(tree),
+        }
+    }
+}
+
+impl sealed::Sealed for [u8; 64] {}
+
+impl BackendKey for [u8; 64] {
+    const BACKENDS: &'static [Backend] = &[Backend::Avx512, Backend::Scalar];
+
+    fn compile_for<V: JitValue>(
+        tree: &AvlTree<Self, V>,
+        backend: Backend,
+    ) -> CompiledLookup<Self, V> {
+        match backend {
+            Backend::Avx512 => jit_avx::compile_avx512(tree),
+            _ => codegen::compile(tree),
+        }
+    }
+}
+
+/// Returns the most preferred backend for `K` that the host CPU supports.
+pub fn detect<K: BackendKey>() -> Backend {
+    K::BACKENDS
+        .iter()
+        .copied()
+        .find(|backend| backend.is_supported())
+        .unwrap_or(Backend::Scalar)
+}
+
+/// Compiles `AvlTree::lookup` for the given tree with the best backend the host CPU supports,
+/// returning the compiled lookup and the backend chosen.
+pub fn compile_auto<K: BackendKey, V: JitValue>(
+    tree: &AvlTree<K, V>,
+) -> (CompiledLookup<K, V>, Backend) {
+    let backend = detect::<K>();
+    (K::compile_for(tree, backend), backend)
+}
+
+/// Compiles `AvlTree::lookup` for the given tree with the given backend, if it supports the key
+/// type and the host CPU supports it.
+pub fn compile_backend<K: BackendKey, V: JitValue>(
+    tree: &AvlTree<K, V>,
+    backend: Backend,
+) -> Result<CompiledLookup<K, V>, BackendError> {
+    if !K::BACKENDS.contains(&backend) {
+        return Err(BackendError::UnsupportedKey { backend });
+    }
+    backend.check()?;
+    Ok(K::compile_for(tree, backend))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::check_against_tree;
+    use rand::prelude::*;
+
+    #[test]
+    fn test_baseline_backends_are_always_supported() {
+        assert!(Backend::Scalar.is_supported());
+        assert_eq!(detect::<i32>(), Backend::Scalar);
+        assert_eq!(detect::<Vec<u8>>(), Backend::Scalar);
+        // x86-64 implies SSE2
+        assert!(Backend::Sse.is_supported());
+        assert_eq!(detect::<[u8; 16]>(), Backend::Sse);
+    }
+
+    #[test]
+    fn test_detect_picks_the_best_supported_backend() {
+        let expected = if Backend::Avx2.is_supported() {
+            Backend::Avx2
+        } else {
+            Backend::Scalar
+        };
+        assert_eq!(detect::<[u8; 32]>(), expected);
+        for backend in [Backend::Sse, Backend::Avx2, Backend::Avx512] {
+            if let Err(BackendError::MissingFeature { feature, .. }) = backend.check() {
+                assert!(backend.required_features().contains(&feature));
+                assert!(!is_feature_detected(feature));
+            }
+        }
+    }
+
+    fn check_compile_auto<const N: usize>(seed: u64)
+    where
+        [u8; N]: BackendKey,
+    {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut random_key = || -> [u8; N] { std::array::from_fn(|_| rng.random()) };
+        let keys: Vec<[u8; N]> = (0..500).map(|_| random_key()).collect();
+        let probes: Vec<[u8; N]> = (0..500).map(|_| random_key()).collect();
+        let tree: AvlTree<[u8; N], usize> = keys.iter().enumerate().map(|(i, &k)| (k, i)).collect();
+
+        let (compiled, backend) = compile_auto(&tree);
+        assert_eq!(backend, detect::<[u8; N]>());
+        check_against_tree(&tree, &probes, |probe| compiled.lookup(probe), None);
+
+        // Every backend either compiles a working lookup or says why it cannot
+        for backend in [
+            Backend::Scalar,
+            Backend::Sse,
+            Backend::Avx2,
+            Backend::Avx512,
+        ] {
+            match compile_backend(&tree, backend) {
+                Ok(compiled) => {
+                    assert!(backend.is_supported());
+                    check_against_tree(&tree, &probes, |probe| compiled.lookup(probe), None);
+                }
+                Err(BackendError::UnsupportedKey { .. }) => {
+                    assert!(!<[u8; N]>::BACKENDS.contains(&backend));
+                }
+                Err(BackendError::MissingFeature { .. }) => assert!(!backend.is_supported()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_compile_auto() {
+        check_compile_auto::<16>(1616);
+        check_compile_auto::<32>(3232);
+        check_compile_auto::<64>(6464);
+    }
+
+    #[test]
+    fn test_compile_backend_rejects_other_key_types() {
+        let tree: AvlTree<i32, i32> = (0..10).map(|k| (k, k)).collect();
+        assert_eq!(
+            compile_backend(&tree, Backend::Sse).err(),
+            Some(BackendError::UnsupportedKey {
+                backend: Backend::Sse
+            })
+        );
+        assert_eq!(
+            compile_backend(&tree, Backend::Scalar).unwrap().lookup(&3),
+            Some(3)
+        );
+    }
+}
@@ -0,0 +1,659 @@
+use crate::avl::{AvlTree, Node};
+use crate::compiled::{CompiledLookup, CompiledNeighbor, CompiledRank, JitValue};
+
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi, dynasm};
+
+pub(crate) use crate::emitter::Emitter;
+
+/// Result of a compiled lookup, returned in rax (presence flag) and rdx (value).
+///
+/// Signalling presence separately keeps every value representable, unlike a sentinel.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawLookup {
+    pub found: u64,
+    pub value: u64,
+}
+
+impl RawLookup {
+    /// Returns the raw value bits if the key was found.
+    pub fn get(self) -> Option<u64> {
+        (self.found != 0).then_some(self.value)
+    }
+}
+
+// Signatures of the compiled functions for a key passed as `A`, see `KeyCodegen::Arg`.
+pub type JittedLookup<A> = unsafe extern "sysv64" fn(key: A) -> RawLookup;
+pub type JittedRank<A> = unsafe extern "sysv64" fn(key: A) -> usize;
+// Neighbor queries write the matched entry to `out` and return true, or return false when there
+// is no such entry
+pub type JittedNeighbor<A, K, V> =
+    unsafe extern "sysv64" fn(key: A, out: *mut KeyValue<K, V>) -> bool;
+// Batched lookups take the keys as stored in a slice and write one result per key
+pub type JittedBatchLookup<K> =
+    unsafe extern "sysv64" fn(keys: *const K, n: usize, out: *mut RawLookup);
+
+/// Describes how compiled tree queries receive keys of a given type.
+///
+/// The trait is sealed: the compiled code is run by safe methods such as
+/// `CompiledLookup::lookup`, so only this crate's key types, whose code follows the ABI of
+/// `EmitKey`, implement it.
+pub trait KeyCodegen: Ord + sealed::EmitKey {
+    /// How the probe key is passed to the compiled code.
+    type Arg: Copy;
+
+    /// Returns the argument to pass for the probe `self`.
+    fn arg(&self) -> Self::Arg;
+}
+
+pub(crate) use sealed::EmitKey;
+
+mod sealed {
+    use crate::emitter::Emitter;
+    use dynasmrt::DynamicLabel;
+
+    /// Describes how compiled tree queries load and compare keys of a given type.
+    ///
+    /// The generated code receives the probe key as `KeyCodegen::Arg` in the argument
+    /// registers, i.e. in rdi, or in rdi and rsi for arguments of two words, and may load it into
+    /// further registers once at function entry. Comparisons may clobber rax, rcx, rdx, the mask
+    /// register k1, and those of r8-r11 and the vector registers 0-3 that do not hold the probe,
+    /// but must leave any further arguments (such as the out pointer of neighbor queries, in rsi)
+    /// alone.
+    pub trait EmitKey {
+        /// Emits the loads of the probe key into the registers `emit_compare` works on. Emitted
+        /// once at function entry, so node blocks only compare and branch; keys compared in rdi
+        /// itself need nothing.
+        fn emit_load_probe(_ops: &mut Emitter) {}
+
+        /// Emits the comparison of the probe key against `self`: jumps to `less` if the probe is
+        /// less than `self`, to `greater` if it is greater, and falls through if they are equal.
+        fn emit_compare(&self, ops: &mut Emitter, less: DynamicLabel, greater: DynamicLabel);
+    }
+}
+
+// Keys of up to 32 bits are passed widened to 32 bits and compared in edi, wider keys in rdi.
+// The node key is widened the same way, so comparing the bit patterns with signed or unsigned
+// branches orders them like the keys themselves.
+macro_rules! impl_int_key_codegen {
+    ($($ty:ty => $arg:ty, $cmp:ident, $branch:ident);* $(;)?) => {
+        $(
+            impl KeyCodegen for $ty {
+                type Arg = $arg;
+
+                fn arg(&self) -> $arg {
+                    *self as $arg
+                }
+            }
+
+            impl EmitKey for $ty {
+                fn emit_compare(
+                    &self,
+                    ops: &mut Emitter,
+                    less: DynamicLabel,
+                    greater: DynamicLabel,
+                ) {
+                    $cmp(ops, *self as $arg as i64);
+                    $branch(ops, less, greater);
+                }
+            }
+        )*
+    };
+}
+
+impl_int_key_codegen!(
+    i8 => i32, cmp_edi, branch_signed;
+    i16 => i32, cmp_edi, branch_signed;
+    i32 => i32, cmp_edi, branch_signed;
+    i64 => i64, cmp_rdi, branch_signed;
+    isize => i64, cmp_rdi, branch_signed;
+    u8 => u32, cmp_edi, branch_unsigned;
+    u16 => u32, cmp_edi, branch_unsigned;
+    u32 => u32, cmp_edi, branch_unsigned;
+    u64 => u64, cmp_rdi, branch_unsigned;
+    usize => u64, cmp_rdi, branch_unsigned;
+);
+
+fn cmp_edi(ops: &mut Emitter, imm: i64) {
+    dynasm!(ops; cmp edi, imm as i32);
+}
+
+fn cmp_rdi(ops: &mut Emitter, imm: i64) {
+    // 32-bit immediates are sign-extended, anything else needs a register
+    if let Ok(imm) = i32::try_from(imm) {
+        dynasm!(ops; cmp rdi, imm);
+    } else {
+        dynasm!(ops
+            ; mov rax, QWORD imm
+            ; cmp rdi, rax
+        );
+    }
+}
+
+fn branch_signed(ops: &mut Emitter, less: DynamicLabel, greater: DynamicLabel) {
+    dynasm!(ops
+        ; jl =>less
+        ; jg =>greater
+    );
+}
+
+pub(crate) fn branch_unsigned(ops: &mut Emitter, less: DynamicLabel, greater: DynamicLabel) {
+    dynasm!(ops
+        ; jb =>less
+        ; ja =>greater
+    );
+}
+
+// Number of leading probe qwords kept in r8-r11
+const PROBE_REGISTERS: usize = 4;
+
+// Byte arrays are passed by pointer and compared as big-endian chunks with unsigned branches:
+// byte-swapping the little-endian loads puts the first byte in the most significant position,
+// so integer order is exactly the lexicographic `Ord` of `[u8; N]`.
+impl<const N: usize> KeyCodegen for [u8; N] {
+    type Arg = *const u8;
+
+    fn arg(&self) -> *const u8 {
+        self.as_ptr()
+    }
+}
+
+impl<const N: usize> EmitKey for [u8; N] {
+    fn emit_load_probe(ops: &mut Emitter) {
+        for chunk in 0..(N / 8).min(PROBE_REGISTERS) {
+            let reg = 8 + chunk as u8;
+            let offset = (chunk * 8) as i32;
+            dynasm!(ops
+                ; mov Rq(reg), QWORD [rdi + offset]
+                ; bswap Rq(reg)
+            );
+        }
+    }
+
+    fn emit_compare(&self, ops: &mut Emitter, less: DynamicLabel, greater: DynamicLabel) {
+        let mut offset = 0;
+
+        while offset + 8 <= N {
+            let chunk = u64::from_be_bytes(self[offset..offset + 8].try_into().unwrap()) as i64;
+            let chunk_index = offset / 8;
+            let probe = if chunk_index < PROBE_REGISTERS {
+                8 + chunk_index as u8
+            } else {
+                // Qwords past the registers are loaded as they are compared, into rcx
+                dynasm!(ops
+                    ; mov rcx, QWORD [rdi + offset as i32]
+                    ; bswap rcx
+                );
+                1
+            };
+            if let Ok(imm) = i32::try_from(chunk) {
+                dynasm!(ops; cmp Rq(probe), imm);
+            } else {
+                dynasm!(ops
+                    ; mov rax, QWORD chunk
+                    ; cmp Rq(probe), rax
+                );
+            }
+            branch_unsigned(ops, less, greater);
+            offset += 8;
+        }
+
+        // The tail shorter than a qword is compared in dword, word and byte chunks, loaded
+        // zero-extended into ecx
+        while offset < N {
+            let width = match N - offset {
+                1 => 1,
+                2 | 3 => 2,
+                _ => 4,
+            };
+            let offset_imm = offset as i32;
+            match width {
+                1 => dynasm!(ops; movzx ecx, BYTE [rdi + offset_imm]),
+                2 => dynasm!(ops
+                    ; movzx ecx, WORD [rdi + offset_imm]
+                    ; rol cx, 8
+                ),
+                _ => dynasm!(ops
+                    ; mov ecx, DWORD [rdi + offset_imm]
+                    ; bswap ecx
+                ),
+            }
+            let mut chunk = [0; 4];
+            chunk[4 - width..].copy_from_slice(&self[offset..offset + width]);
+            dynasm!(ops; cmp ecx, u32::from_be_bytes(chunk) as i32);
+            branch_unsigned(ops, less, greater);
+            offset += width;
+        }
+    }
+}
+
+/// Orders the keys of a vector comparison by their first differing byte, at the index in rax,
+/// with the probe in rdi and the node key in rdx.
+pub(crate) fn branch_first_difference(
+    ops: &mut Emitter,
+    less: DynamicLabel,
+    greater: DynamicLabel,
+) {
+    dynasm!(ops
+        ; movzx ecx, BYTE [rdi + rax]
+        ; cmp cl, BYTE [rdx + rax]
+        ; jb =>less
+        ; jmp =>greater
+    );
+}
+
+/// The key-specific parts of the generated code.
+///
+/// `KeyOps::scalar` uses the `EmitKey` impl of the key type; compilers using other
+/// instruction sets for the same key type (such as `jit_sse::compile_sse`) provide their own.
+pub(crate) struct KeyOps<K> {
+    /// Emitted once at function entry, see `EmitKey::emit_load_probe`.
+    pub load_probe: fn(&mut Emitter),
+    pub compare: fn(&K, &mut Emitter, DynamicLabel, DynamicLabel),
+}
+
+impl<K: KeyCodegen> KeyOps<K> {
+    pub fn scalar() -> Self {
+        KeyOps {
+            load_probe: K::emit_load_probe,
+            compare: K::emit_compare,
+        }
+    }
+}
+
+/// Compiles `AvlTree::lookup` for the given tree.
+pub fn compile<K: KeyCodegen, V: JitValue>(tree: &AvlTree<K, V>) -> CompiledLookup<K, V> {
+    compile_with(tree, &KeyOps::scalar())
+}
+
+/// Compiles `AvlTree::lookup` for the given tree with the given key code.
+pub(crate) fn compile_with<K: Ord, V: JitValue>(
+    tree: &AvlTree<K, V>,
+    key_ops: &KeyOps<K>,
+) -> CompiledLookup<K, V> {
+    let mut ops = Emitter::new();
+
+    let start = ops.offset();
+    (key_ops.load_probe)(&mut ops);
+
+    // The label for the "not found" case, which missing children branch to
+    let not_found_label = ops.new_dynamic_label();
+
+    // Recursively build the assembly from the tree structure
+    if let Some(node) = &tree.root {
+        build_lookup_asm(&mut ops, node, key_ops, not_found_label);
+    }
+
+    // "Not found" block: clear the presence flag (rax) and return
+    dynasm!(ops
+        ; =>not_found_label
+        ; xor eax, eax
+    );
+    ops.emit_ret();
+
+    // Finalize the buffer and hand it over to the owning handle
+    let buf = ops.finalize();
+    CompiledLookup::new(buf, start, tree.version())
+}
+
+// Recursive helper to generate the lookup code for a subtree, in pre-order: the node's block
+// falls through into its "found" epilogue, followed by the blocks of its children.
+fn build_lookup_asm<K: Ord, V: JitValue>(
+    ops: &mut Emitter,
+    node: &Node<K, V>,
+    key_ops: &KeyOps<K>,
+    not_found_label: DynamicLabel,
+) {
+    let left_label = child_label(ops, &node.left, not_found_label);
+    let right_label = child_label(ops, &node.right, not_found_label);
+
+    (key_ops.compare)(&node.key, ops, left_label, right_label);
+    emit_found(ops, node.value.to_bits());
+
+    if let Some(left) = &node.left {
+        dynasm!(ops; =>left_label);
+        build_lookup_asm(ops, left, key_ops, not_found_label);
+    }
+    if let Some(right) = &node.right {
+        dynasm!(ops; =>right_label);
+        build_lookup_asm(ops, right, key_ops, not_found_label);
+    }
+}
+
+// Returns a fresh label for the code block of `child`, or `missing` if there is no child
+pub(crate) fn child_label<K: Ord, V>(
+    ops: &mut Emitter,
+    child: &Option<Box<Node<K, V>>>,
+    missing: DynamicLabel,
+) -> DynamicLabel {
+    match child {
+        Some(_) => ops.new_dynamic_label(),
+        None => missing,
+    }
+}
+
+/// Emits the "found" epilogue of a lookup: moves the value bits into rdx, sets the presence
+/// flag in rax and returns.
+pub(crate) fn emit_found(ops: &mut Emitter, bits: u64) {
+    // 32-bit moves zero-extend and 32-bit immediates of 64-bit moves sign-extend, so only
+    // wider values need the 10-byte immediate form
+    if let Ok(bits) = u32::try_from(bits) {
+        dynasm!(ops; mov edx, bits as i32);
+    } else if let Ok(bits) = i32::try_from(bits as i64) {
+        dynasm!(ops; mov rdx, DWORD bits);
+    } else {
+        dynasm!(ops; mov rdx, QWORD bits as i64);
+    }
+    dynasm!(ops; mov eax, 1);
+    ops.emit_ret();
+}
+
+/// Compiles `AvlTree::rank` for the given tree.
+pub fn compile_rank<K: KeyCodegen, V>(tree: &AvlTree<K, V>) -> CompiledRank<K> {
+    compile_rank_with(tree, &KeyOps::scalar())
+}
+
+/// Compiles `AvlTree::rank` for the given tree with the given key code.
+pub(crate) fn compile_rank_with<K: KeyCodegen, V>(
+    tree: &AvlTree<K, V>,
+    key_ops: &KeyOps<K>,
+) -> CompiledRank<K> {
+    let mut ops = Emitter::new();
+
+    let start = ops.offset();
+    (key_ops.load_probe)(&mut ops);
+
+    match &tree.root {
+        Some(node) => build_rank_asm(&mut ops, node, key_ops, 0),
+        None => emit_rank(&mut ops, 0),
+    }
+
+    CompiledRank::new(ops.finalize(), start, tree.version())
+}
+
+// Recursive helper to generate the rank code for a subtree.
+//
+// Every node is reached along a single path, so the number of keys below its subtree (`base`)
+// is known at compile time and every outcome simply returns an immediate.
+fn build_rank_asm<K: Ord, V>(
+    ops: &mut Emitter,
+    node: &Node<K, V>,
+    key_ops: &KeyOps<K>,
+    base: usize,
+) {
+    let left_label = ops.new_dynamic_label();
+    let right_label = ops.new_dynamic_label();
+    let equal_rank = base + Node::size(&node.left);
+
+    (key_ops.compare)(&node.key, ops, left_label, right_label);
+    emit_rank(ops, equal_rank);
+
+    dynasm!(ops; =>left_label);
+    match &node.left {
+        Some(left) => build_rank_asm(ops, left, key_ops, base),
+        None => emit_rank(ops, base),
+    }
+
+    dynasm!(ops; =>right_label);
+    match &node.right {
+        Some(right) => build_rank_asm(ops, right, key_ops, equal_rank + 1),
+        None => emit_rank(ops, equal_rank + 1),
+    }
+}
+
+fn emit_rank(ops: &mut Emitter, rank: usize) {
+    dynasm!(ops; mov rax, QWORD rank as i64);
+    ops.emit_ret();
+}
+
+/// The neighbor queries of `AvlTree` that can be compiled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Neighbor {
+    /// Greatest key less than or equal to the probe.
+    Floor,
+    /// Smallest key greater than or equal to the probe.
+    Ceiling,
+    /// Greatest key strictly less than the probe.
+    Predecessor,
+    /// Smallest key strictly greater than the probe.
+    Successor,
+}
+
+/// A key and its value as written out by compiled neighbor queries.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyValue<K, V> {
+    pub key: K,
+    pub value: V,
+}
+
+/// Outcome of a neighbor query once the search has left the tree at some node, given the
+/// nearest ancestors below (`lo`) and above (`hi`) the probe on the path to it.
+///
+/// The path to every node is unique, so each outcome is known at compile time.
+pub(crate) struct NeighborPath<'a, K: Ord, V> {
+    pub lo: Option<&'a Node<K, V>>,
+    pub hi: Option<&'a Node<K, V>>,
+}
+
+impl<K: Ord, V> Clone for NeighborPath<'_, K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K: Ord, V> Copy for NeighborPath<'_, K, V> {}
+
+impl<'a, K: Ord, V> NeighborPath<'a, K, V> {
+    /// Result when the probe equals `node`.
+    pub fn equal(self, kind: Neighbor, node: &'a Node<K, V>) -> Option<&'a Node<K, V>> {
+        match kind {
+            Neighbor::Floor | Neighbor::Ceiling => Some(node),
+            Neighbor::Predecessor => match node.left.as_deref() {
+                Some(mut max) => {
+                    while let Some(right) = max.right.as_deref() {
+                        max = right;
+                    }
+                    Some(max)
+                }
+                None => self.lo,
+            },
+            Neighbor::Successor => match node.right.as_deref() {
+                Some(mut min) => {
+                    while let Some(left) = min.left.as_deref() {
+                        min = left;
+                    }
+                    Some(min)
+                }
+                None => self.hi,
+            },
+        }
+    }
+
+    /// Result when the probe is less than `node`, which has no left child.
+    pub fn below(self, kind: Neighbor, node: &'a Node<K, V>) -> Option<&'a Node<K, V>> {
+        match kind {
+            Neighbor::Floor | Neighbor::Predecessor => self.lo,
+            Neighbor::Ceiling | Neighbor::Successor => Some(node),
+        }
+    }
+
+    /// Result when the probe is greater than `node`, which has no right child.
+    pub fn above(self, kind: Neighbor, node: &'a Node<K, V>) -> Option<&'a Node<K, V>> {
+        match kind {
+            Neighbor::Floor | Neighbor::Predecessor => Some(node),
+            Neighbor::Ceiling | Neighbor::Successor => self.hi,
+        }
+    }
+}
+
+// Emits the code that writes a key to the `key` field of the `KeyValue` at the out pointer (in
+// rsi). How keys are written out depends on the key type.
+pub(crate) type EmitNeighborKey<K> = fn(&mut Emitter, &K);
+
+/// Compiles the given neighbor query (`AvlTree::floor` and friends) for the given tree.
+pub(crate) fn compile_neighbor_with<K: Ord, V: JitValue>(
+    tree: &AvlTree<K, V>,
+    kind: Neighbor,
+    key_ops: &KeyOps<K>,
+    emit_key: EmitNeighborKey<K>,
+) -> CompiledNeighbor<K, V> {
+    let mut ops = Emitter::new();
+
+    let start = ops.offset();
+    (key_ops.load_probe)(&mut ops);
+
+    let path = NeighborPath { lo: None, hi: None };
+    match &tree.root {
+        Some(node) => build_neighbor_asm(&mut ops, node, kind, path, key_ops, emit_key),
+        None => emit_neighbor_result::<K, V>(&mut ops, None, emit_key),
+    }
+
+    CompiledNeighbor::new(ops.finalize(), start, tree.version())
+}
+
+// Recursive helper to generate the neighbor query code for a subtree
+fn build_neighbor_asm<'a, K: Ord, V: JitValue>(
+    ops: &mut Emitter,
+    node: &'a Node<K, V>,
+    kind: Neighbor,
+    path: NeighborPath<'a, K, V>,
+    key_ops: &KeyOps<K>,
+    emit_key: EmitNeighborKey<K>,
+) {
+    let left_label = ops.new_dynamic_label();
+    let right_label = ops.new_dynamic_label();
+
+    (key_ops.compare)(&node.key, ops, left_label, right_label);
+    emit_neighbor_result(ops, path.equal(kind, node), emit_key);
+
+    dynasm!(ops; =>left_label);
+    match &node.left {
+        Some(left) => {
+            let path = NeighborPath {
+                hi: Some(node),
+                ..path
+            };
+            build_neighbor_asm(ops, left, kind, path, key_ops, emit_key);
+        }
+        None => emit_neighbor_result(ops, path.below(kind, node), emit_key),
+    }
+
+    dynasm!(ops; =>right_label);
+    match &node.right {
+        Some(right) => {
+            let path = NeighborPath {
+                lo: Some(node),
+                ..path
+            };
+            build_neighbor_asm(ops, right, kind, path, key_ops, emit_key);
+        }
+        None => emit_neighbor_result(ops, path.above(kind, node), emit_key),
+    }
+}
+
+// Writes the entry to the out pointer (in rsi) and returns whether there is one. The value is
+// stored at its offset in `KeyValue<K, V>` and at its full width.
+fn emit_neighbor_result<K: Ord, V: JitValue>(
+    ops: &mut Emitter,
+    result: Option<&Node<K, V>>,
+    emit_key: EmitNeighborKey<K>,
+) {
+    match result {
+        Some(node) => {
+            emit_key(ops, &node.key);
+            let offset = std::mem::offset_of!(KeyValue<K, V>, value) as i32;
+            let bits = node.value.to_bits();
+            match size_of::<V>() {
+                1 => dynasm!(ops; mov BYTE [rsi + offset], bits as i8),
+                2 => dynasm!(ops; mov WORD [rsi + offset], bits as i16),
+                4 => dynasm!(ops; mov DWORD [rsi + offset], bits as i32),
+                _ => match i32::try_from(bits as i64) {
+                    Ok(imm) => dynasm!(ops; mov QWORD [rsi + offset], imm),
+                    Err(_) => dynasm!(ops
+                        ; mov rax, QWORD bits as i64
+                        ; mov [rsi + offset], rax
+                    ),
+                },
+            }
+            dynasm!(ops; mov eax, 1);
+        }
+        None => dynasm!(ops; xor eax, eax),
+    }
+    ops.emit_ret();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{alphabet_key, check_against_tree};
+    use rand::prelude::*;
+    use std::fmt::Debug;
+
+    // Checks `compile` and `compile_rank` for a tree of the given keys
+    fn check_compile<K: KeyCodegen + Copy + Debug>(keys: &[K], probes: &[K]) {
+        let tree: AvlTree<K, usize> = keys.iter().enumerate().map(|(i, &k)| (k, i)).collect();
+        let compiled = compile(&tree);
+        let compiled_rank = compile_rank(&tree);
+        check_against_tree(
+            &tree,
+            probes,
+            |probe| compiled.lookup(probe),
+            Some(&compiled_rank),
+        );
+    }
+
+    macro_rules! int_key_tests {
+        ($($name:ident: $ty:ty),* $(,)?) => {
+            $(
+                #[test]
+                fn $name() {
+                    let mut rng = StdRng::seed_from_u64(<$ty>::BITS as u64);
+                    let mut keys: Vec<$ty> = vec![<$ty>::MIN, <$ty>::MAX, 0, 1, <$ty>::MAX / 2];
+                    keys.extend((0..300).map(|_| rng.random::<$ty>()));
+                    // Neighbors of the keys, and values straddling the sign bit
+                    let mut probes: Vec<$ty> = keys
+                        .iter()
+                        .flat_map(|&k| [k.wrapping_sub(1), k.wrapping_add(1)])
+                        .collect();
+                    probes.extend([<$ty>::MAX / 2 + 1, <$ty>::MIN.wrapping_add(1)]);
+                    check_compile(&keys, &probes);
+                }
+            )*
+        };
+    }
+
+    int_key_tests!(
+        test_i8_keys: i8,
+        test_i16_keys: i16,
+        test_i32_keys: i32,
+        test_i64_keys: i64,
+        test_u8_keys: u8,
+        test_u16_keys: u16,
+        test_u32_keys: u32,
+        test_u64_keys: u64,
+    );
+
+    fn check_byte_array_keys<const N: usize>() {
+        let mut rng = StdRng::seed_from_u64(N as u64);
+        let keys: Vec<[u8; N]> = (0..300).map(|_| alphabet_key(&mut rng)).collect();
+        let probes: Vec<[u8; N]> = (0..300).map(|_| alphabet_key(&mut rng)).collect();
+        check_compile(&keys, &probes);
+    }
+
+    #[test]
+    fn test_byte_array_keys() {
+        check_byte_array_keys::<1>();
+        check_byte_array_keys::<2>();
+        check_byte_array_keys::<3>();
+        check_byte_array_keys::<5>();
+        check_byte_array_keys::<7>();
+        check_byte_array_keys::<8>();
+        check_byte_array_keys::<12>();
+        check_byte_array_keys::<15>();
+        check_byte_array_keys::<16>();
+        check_byte_array_keys::<20>();
+        check_byte_array_keys::<32>();
+        check_byte_array_keys::<41>();
+    }
+}
@@ -0,0 +1,420 @@
+use crate::avl::AvlTree;
+use crate::backend::{self, BackendKey};
+use crate::codegen::{
+    JittedBatchLookup, JittedLookup, JittedNeighbor, JittedRank, KeyCodegen, KeyValue, RawLookup,
+};
+
+use dynasmrt::{AssemblyOffset, ExecutableBuffer};
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+
+pub use code::CompiledCode;
+
+mod code {
+    use crate::avl::AvlTree;
+
+    use dynasmrt::{AssemblyOffset, ExecutableBuffer};
+    use std::marker::PhantomData;
+
+    /// The code of a compiled query on an `AvlTree<K, _>`, shared by all compiled handles.
+    ///
+    /// The handle owns the executable buffer the code lives in, so the code stays valid for as
+    /// long as the handle does and can be called through the safe methods of the typed handles,
+    /// from any thread.
+    ///
+    /// The compiled code is a snapshot of the tree: it records the version of the tree it was
+    /// compiled from, and `is_stale` tells whether the tree has changed since.
+    pub struct CompiledCode<K> {
+        buf: ExecutableBuffer,
+        entry: AssemblyOffset,
+        pub(super) version: u64,
+        _marker: PhantomData<fn(&K)>,
+    }
+
+    impl<K> CompiledCode<K> {
+        pub(super) fn new(buf: ExecutableBuffer, entry: AssemblyOffset, version: u64) -> Self {
+            CompiledCode {
+                buf,
+                entry,
+                version,
+                _marker: PhantomData,
+            }
+        }
+
+        /// Returns the version of the tree the code was compiled from.
+        pub fn version(&self) -> u64 {
+            self.version
+        }
+
+        /// Returns true if `tree` has changed since the code was compiled from it, or if the
+        /// code was compiled from another tree.
+        pub fn is_stale<V>(&self, tree: &AvlTree<K, V>) -> bool
+        where
+            K: Ord,
+        {
+            tree.version() != self.version
+        }
+
+        /// Returns the size of the generated code in bytes.
+        pub fn code_size(&self) -> usize {
+            self.buf.size()
+        }
+
+        /// Returns the compiled function as a pointer of type `F`.
+        ///
+        /// # Safety
+        ///
+        /// `F` must be the signature of the function at `entry`. The pointer is only valid for
+        /// as long as `self` lives.
+        pub(super) unsafe fn func<F: Copy>(&self) -> F {
+            assert_eq!(size_of::<F>(), size_of::<*const u8>());
+            unsafe { std::mem::transmute_copy(&self.buf.ptr(self.entry)) }
+        }
+    }
+}
+
+/// A JIT-compiled lookup function for an `AvlTree<K, V>`, see `CompiledCode`.
+pub struct CompiledLookup<K, V> {
+    code: CompiledCode<K>,
+    _marker: PhantomData<fn(&K) -> V>,
+}
+
+impl<K, V> CompiledLookup<K, V> {
+    /// Wraps finalized code whose function starts at `entry`.
+    ///
+    /// The code at `entry` must implement `JittedLookup<K::Arg>`, returning values of `V`.
+    pub(crate) fn new(buf: ExecutableBuffer, entry: AssemblyOffset, version: u64) -> Self {
+        CompiledLookup {
+            code: CompiledCode::new(buf, entry, version),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K: KeyCodegen, V: JitValue> CompiledLookup<K, V> {
+    /// Looks up `key` with the compiled code.
+    pub fn lookup(&self, key: &K) -> Option<V> {
+        let func: JittedLookup<K::Arg> = unsafe { self.code.func() };
+        unsafe { func(key.arg()) }.get().map(V::from_bits)
+    }
+}
+
+impl<K, V> Deref for CompiledLookup<K, V> {
+    type Target = CompiledCode<K>;
+
+    fn deref(&self) -> &CompiledCode<K> {
+        &self.code
+    }
+}
+
+/// A JIT-compiled function looking up a batch of keys at once, see `jit_eytzinger::compile_batch`.
+pub struct CompiledBatchLookup<K, V> {
+    code: CompiledCode<K>,
+    _marker: PhantomData<fn(&K) -> V>,
+}
+
+// Results are written out by the compiled code in chunks of this many keys
+const BATCH_CHUNK: usize = 256;
+
+impl<K, V> CompiledBatchLookup<K, V> {
+    /// Wraps finalized code whose function starts at `entry`.
+    ///
+    /// The code at `entry` must implement `JittedBatchLookup<K>` for keys stored as `K`.
+    pub(crate) fn new(buf: ExecutableBuffer, entry: AssemblyOffset, version: u64) -> Self {
+        CompiledBatchLookup {
+            code: CompiledCode::new(buf, entry, version),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Looks up every key of `keys`, writing the raw results to the same positions of `out`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `out` is shorter than `keys`.
+    pub fn lookup_batch_raw(&self, keys: &[K], out: &mut [RawLookup]) {
+        assert!(out.len() >= keys.len(), "output shorter than the keys");
+        let func: JittedBatchLookup<K> = unsafe { self.code.func() };
+        unsafe { func(keys.as_ptr(), keys.len(), out.as_mut_ptr()) }
+    }
+}
+
+impl<K, V: JitValue> CompiledBatchLookup<K, V> {
+    /// Looks up every key of `keys`, writing the values found to the same positions of `out`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `out` is shorter than `keys`.
+    pub fn lookup_batch(&self, keys: &[K], out: &mut [Option<V>]) {
+        assert!(out.len() >= keys.len(), "output shorter than the keys");
+        let mut raw = [RawLookup { found: 0, value: 0 }; BATCH_CHUNK];
+        for (keys, out) in keys.chunks(BATCH_CHUNK).zip(out.chunks_mut(BATCH_CHUNK)) {
+            self.lookup_batch_raw(keys, &mut raw);
+            for (out, raw) in out.iter_mut().zip(&raw[..keys.len()]) {
+                *out = raw.get().map(V::from_bits);
+            }
+        }
+    }
+}
+
+impl<K, V> Deref for CompiledBatchLookup<K, V> {
+    type Target = CompiledCode<K>;
+
+    fn deref(&self) -> &CompiledCode<K> {
+        &self.code
+    }
+}
+
+/// A JIT-compiled rank query for the keys of an `AvlTree<K, _>`, see `codegen::compile_rank`.
+pub struct CompiledRank<K> {
+    code: CompiledCode<K>,
+}
+
+impl<K> CompiledRank<K> {
+    /// Wraps finalized code whose function starts at `entry`.
+    ///
+    /// The code at `entry` must implement `JittedRank<K::Arg>`.
+    pub(crate) fn new(buf: ExecutableBuffer, entry: AssemblyOffset, version: u64) -> Self {
+        CompiledRank {
+            code: CompiledCode::new(buf, entry, version),
+        }
+    }
+}
+
+impl<K: KeyCodegen> CompiledRank<K> {
+    /// Returns the number of keys of the tree less than `key`, like `AvlTree::rank`.
+    pub fn rank(&self, key: &K) -> usize {
+        let func: JittedRank<K::Arg> = unsafe { self.code.func() };
+        unsafe { func(key.arg()) }
+    }
+}
+
+impl<K> Deref for CompiledRank<K> {
+    type Target = CompiledCode<K>;
+
+    fn deref(&self) -> &CompiledCode<K> {
+        &self.code
+    }
+}
+
+/// A JIT-compiled neighbor query (`AvlTree::floor` and friends), see `jit::compile_neighbor`.
+pub struct CompiledNeighbor<K, V> {
+    code: CompiledCode<K>,
+    _marker: PhantomData<fn(&K) -> V>,
+}
+
+impl<K, V> CompiledNeighbor<K, V> {
+    /// Wraps finalized code whose function starts at `entry`.
+    ///
+    /// The code at `entry` must implement `JittedNeighbor<K::Arg, K, V>`, writing entries laid
+    /// out as `KeyValue<K, V>`.
+    pub(crate) fn new(buf: ExecutableBuffer, entry: AssemblyOffset, version: u64) -> Self {
+        CompiledNeighbor {
+            code: CompiledCode::new(buf, entry, version),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K: KeyCodegen, V> CompiledNeighbor<K, V> {
+    /// Returns the entry the query matches for `key`, or `None` if there is no such entry.
+    pub fn neighbor(&self, key: &K) -> Option<KeyValue<K, V>> {
+        let mut out = MaybeUninit::uninit();
+        let func: JittedNeighbor<K::Arg, K, V> = unsafe { self.code.func() };
+        // The compiled code initializes `out` whenever it returns true
+        unsafe { func(key.arg(), out.as_mut_ptr()) }.then(|| unsafe { out.assume_init() })
+    }
+}
+
+impl<K, V> Deref for CompiledNeighbor<K, V> {
+    type Target = CompiledCode<K>;
+
+    fn deref(&self) -> &CompiledCode<K> {
+        &self.code
+    }
+}
+
+/// Values that compiled lookups return directly in a register.
+pub trait JitValue: Copy {
+    /// Returns the value as a 64-bit pattern to embed in the generated code.
+    fn to_bits(self) -> u64;
+
+    /// Recovers the value from the pattern returned by the generated code.
+    fn from_bits(bits: u64) -> Self;
+}
+
+macro_rules! impl_jit_value {
+    ($($ty:ty),*) => {
+        $(
+            impl JitValue for $ty {
+                fn to_bits(self) -> u64 {
+                    self as u64
+                }
+
+                fn from_bits(bits: u64) -> Self {
+                    bits as $ty
+                }
+            }
+        )*
+    };
+}
+
+impl_jit_value!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+/// A JIT-compiled lookup returning references to values of any type.
+///
+/// The values are cloned into an arena owned by the handle, and the compiled code returns the
+/// address of a value in the arena instead of the value itself.
+pub struct CompiledRefLookup<K, V> {
+    inner: CompiledLookup<K, usize>,
+    // Never resized, so the addresses baked into the code stay valid
+    values: Box<[V]>,
+}
+
+impl<K: BackendKey + Clone, V: Clone> CompiledRefLookup<K, V> {
+    /// Compiles a lookup for `tree` with the best backend the host CPU supports, see
+    /// `backend::compile_auto`.
+    pub fn compile(tree: &AvlTree<K, V>) -> Self {
+        let values: Box<[V]> = tree.values().cloned().collect();
+        let addresses = AvlTree::from_sorted_iter(
+            tree.keys()
+                .cloned()
+                .zip(values.iter())
+                .map(|(key, value)| (key, value as *const V as usize)),
+        );
+
+        // The code is compiled here from `addresses`, so every address it returns points into
+        // `values`
+        let (mut inner, _) = backend::compile_auto(&addresses);
+        inner.code.version = tree.version();
+        CompiledRefLookup { inner, values }
+    }
+}
+
+impl<K, V> CompiledRefLookup<K, V> {
+    /// Returns the values the compiled code points into, in key order.
+    pub fn values(&self) -> &[V] {
+        &self.values
+    }
+}
+
+impl<K: KeyCodegen, V> CompiledRefLookup<K, V> {
+    /// Looks up `key` with the compiled code.
+    pub fn lookup(&self, key: &K) -> Option<&V> {
+        // Addresses point into `self.values`, which lives as long as `self`
+        self.inner
+            .lookup(key)
+            .map(|address| unsafe { &*(address as *const V) })
+    }
+}
+
+impl<K, V> Deref for CompiledRefLookup<K, V> {
+    type Target = CompiledCode<K>;
+
+    fn deref(&self) -> &CompiledCode<K> {
+        &self.inner.code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{jit, jit_sse};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_compiled_lookup_is_send_and_sync() {
+        assert_send_sync::<CompiledLookup<i32, i32>>();
+        assert_send_sync::<CompiledLookup<[u8; 16], i32>>();
+
+        let tree: AvlTree<i32, i32> = (0..1000).map(|k| (k, k)).collect();
+        let compiled = jit::compile(&tree);
+        std::thread::scope(|scope| {
+            for offset in 0..4 {
+                let compiled = &compiled;
+                scope.spawn(move || {
+                    for key in (offset..1000).step_by(4) {
+                        assert_eq!(compiled.lookup(&key), Some(key));
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_compiled_lookup_tracks_tree_version() {
+        let mut tree: AvlTree<i32, i32> = (0..10).map(|k| (k, k)).collect();
+        let other: AvlTree<i32, i32> = (0..10).map(|k| (k, k)).collect();
+        let compiled = jit::compile(&tree);
+        assert_eq!(compiled.version(), tree.version());
+        assert!(!compiled.is_stale(&tree));
+        assert!(compiled.is_stale(&other));
+
+        tree.remove(&3);
+        assert!(compiled.is_stale(&tree));
+        // The snapshot still answers for the tree it was compiled from
+        assert_eq!(compiled.lookup(&3), Some(3));
+        assert!(!jit::compile(&tree).is_stale(&tree));
+    }
+
+    #[test]
+    fn test_wide_values() {
+        let keys = [-3, -2, -1, 0, 1, 2];
+        let values = [
+            u64::MAX,
+            1 << 63,
+            u32::MAX as u64 + 1,
+            0,
+            1,
+            0xDEAD_BEEF_CAFE_F00D,
+        ];
+        let tree: AvlTree<i32, u64> = keys.into_iter().zip(values).collect();
+        let compiled = jit::compile(&tree);
+        for (key, value) in keys.into_iter().zip(values) {
+            assert_eq!(compiled.lookup(&key), Some(value));
+        }
+
+        let tree: AvlTree<i32, i64> = keys
+            .into_iter()
+            .map(|k| (k, i64::MIN + (k + 3) as i64))
+            .collect();
+        let compiled = jit::compile(&tree);
+        assert_eq!(compiled.lookup(&-3), Some(i64::MIN));
+        assert_eq!(compiled.lookup(&2), Some(i64::MIN + 5));
+        assert_eq!(compiled.lookup(&3), None);
+
+        let tree: AvlTree<[u8; 16], usize> = (0..=255u8)
+            .map(|b| ([b; 16], usize::MAX - b as usize))
+            .collect();
+        for compiled in [jit_sse::compile_scalar(&tree), jit_sse::compile_sse(&tree)] {
+            for b in 0..=255u8 {
+                assert_eq!(compiled.lookup(&[b; 16]), Some(usize::MAX - b as usize));
+            }
+        }
+    }
+
+    #[test]
+    fn test_ref_lookup_returns_arena_values() {
+        let mut tree: AvlTree<i32, String> = AvlTree::new();
+        for key in 0..500 {
+            tree.insert(key * 2, format!("row {key}"));
+        }
+        let compiled = CompiledRefLookup::compile(&tree);
+        assert!(!compiled.is_stale(&tree));
+        for key in 0..1000 {
+            assert_eq!(compiled.lookup(&key), tree.get(&key));
+        }
+
+        // The arena is owned by the handle and survives both moves and the tree
+        let moved = compiled;
+        drop(tree);
+        assert_eq!(moved.lookup(&998).map(String::as_str), Some("row 499"));
+
+        let tree: AvlTree<[u8; 16], Vec<u8>> = (0..=255u8).map(|b| ([b; 16], vec![b; 3])).collect();
+        let compiled = CompiledRefLookup::compile(&tree);
+        assert_eq!(compiled.lookup(&[9; 16]), Some(&vec![9; 3]));
+        assert_eq!(compiled.values().len(), 256);
+    }
+}
@@ -0,0 +1,103 @@
+use dynasmrt::x64::Assembler;
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi, ExecutableBuffer, dynasm};
+use std::ops::{Deref, DerefMut};
+
+/// The assembler a compiled function is emitted into, along with the data and subroutines its
+/// code refers to, which are emitted after the code.
+///
+/// It dereferences to the underlying `Assembler`, so it can be used with `dynasm!` directly.
+pub struct Emitter {
+    ops: Assembler,
+    // Constant pool entries: label, alignment and bytes
+    constants: Vec<(DynamicLabel, usize, Vec<u8>)>,
+    // Shared subroutines, by name, emitted in the order they were first referred to
+    routines: Vec<(&'static str, DynamicLabel, EmitRoutine)>,
+    // Whether the code writes the upper halves of ymm or zmm registers
+    wide_vectors: bool,
+}
+
+// Emits the body of a subroutine, see `Emitter::routine`
+type EmitRoutine = fn(&mut Emitter);
+
+impl Emitter {
+    pub fn new() -> Self {
+        Emitter {
+            ops: Assembler::new().unwrap(),
+            constants: Vec::new(),
+            routines: Vec::new(),
+            wide_vectors: false,
+        }
+    }
+
+    /// Records that the code writes the upper halves of ymm or zmm registers, so that
+    /// `emit_ret` clears them before returning to avoid AVX-SSE transition stalls in the caller.
+    pub fn use_wide_vectors(&mut self) {
+        self.wide_vectors = true;
+    }
+
+    /// Emits a return from the compiled function.
+    pub fn emit_ret(&mut self) {
+        if self.wide_vectors {
+            dynasm!(self.ops; vzeroupper);
+        }
+        dynasm!(self.ops; ret);
+    }
+
+    /// Returns the label of `bytes` in the constant pool, aligned to `align` bytes.
+    pub fn constant(&mut self, bytes: &[u8], align: usize) -> DynamicLabel {
+        let label = self.ops.new_dynamic_label();
+        self.constants.push((label, align, bytes.to_vec()));
+        label
+    }
+
+    /// Returns the label of the subroutine called `name`, which `emit` emits once after the code.
+    pub fn routine(&mut self, name: &'static str, emit: EmitRoutine) -> DynamicLabel {
+        if let Some(&(_, label, _)) = self.routines.iter().find(|(n, _, _)| *n == name) {
+            return label;
+        }
+        let label = self.ops.new_dynamic_label();
+        self.routines.push((name, label, emit));
+        label
+    }
+
+    /// Emits the subroutines and the constant pool, and finalizes the code.
+    pub fn finalize(mut self) -> ExecutableBuffer {
+        // Subroutines may refer to further subroutines and constants
+        let mut emitted = 0;
+        while let Some(&(_, label, emit)) = self.routines.get(emitted) {
+            dynasm!(self.ops; =>label);
+            emit(&mut self);
+            emitted += 1;
+        }
+
+        for (label, align, bytes) in std::mem::take(&mut self.constants) {
+            dynasm!(self.ops
+                ; .align align
+                ; =>label
+            );
+            self.ops.extend(bytes);
+        }
+
+        self.ops.finalize().unwrap()
+    }
+}
+
+impl Default for Emitter {
+    fn default() -> Self {
+        Emitter::new()
+    }
+}
+
+impl Deref for Emitter {
+    type Target = Assembler;
+
+    fn deref(&self) -> &Assembler {
+        &self.ops
+    }
+}
+
+impl DerefMut for Emitter {
+    fn deref_mut(&mut self) -> &mut Assembler {
+        &mut self.ops
+    }
+}
@@ -1,98 +1,559 @@
-use crate::avl::Node;
+use crate::avl::AvlTree;
+use crate::codegen::{self, Emitter, KeyCodegen, KeyOps};
+use crate::compiled::{CompiledLookup, CompiledNeighbor, CompiledRank, JitValue};
 
-use dynasmrt::{DynasmApi, DynasmLabelApi, ExecutableBuffer, dynasm};
-use std::collections::HashMap;
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi, dynasm};
 
-// The function signature we are compiling to: takes a key, returns a value or -1
-//
-// In case we want to return generic values we would need to have their layout somewhat fixed
-// and return a pointer to them.
-pub type JittedLookup = unsafe extern "sysv64" fn(key: i32) -> i32;
+pub use crate::codegen::{KeyValue, Neighbor, RawLookup};
 
-pub fn compile(root: &Option<Box<Node<i32, i32>>>) -> (ExecutableBuffer, JittedLookup) {
-    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+// Keeps the set of `IntKey` types closed: `PackedKey` relies on the keys being plain integers
+mod sealed {
+    pub trait Sealed {}
+}
 
-    let start = ops.offset();
+/// Integer keys, compared in edi or rdi with signed or unsigned branches as their type demands.
+///
+/// The trait is sealed: it is implemented for the primitive integer types only.
+pub trait IntKey: KeyCodegen + Copy + sealed::Sealed {}
 
-    // A map from node key to the dynasm label for that node's code block
-    let mut labels = HashMap::new();
+macro_rules! impl_int_key {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            impl IntKey for $ty {}
+        )*
+    };
+}
 
-    // The label for the "not found" case
-    let not_found_label = ops.new_dynamic_label();
+impl_int_key!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+/// Integer keys that compiled code searches in packed arrays, such as `jit_eytzinger::compile`.
+pub trait PackedKey: IntKey {
+    /// Whether the key is passed in rdi rather than edi, see `KeyCodegen::Arg`.
+    const WIDE: bool;
+    /// Whether the key is signed.
+    const SIGNED: bool;
+
+    /// Returns the key widened like its `KeyCodegen::Arg`, with the sign bit of signed keys
+    /// flipped so that unsigned order is key order.
+    fn ordered_bits(self) -> u64;
+}
+
+macro_rules! impl_packed_key {
+    ($($ty:ty => $arg:ty, $wide:expr, $signed:expr);* $(;)?) => {
+        $(
+            impl PackedKey for $ty {
+                const WIDE: bool = $wide;
+                const SIGNED: bool = $signed;
+
+                fn ordered_bits(self) -> u64 {
+                    let bits = self as $arg as u64;
+                    match $signed {
+                        true => bits ^ 1 << (<$arg>::BITS - 1),
+                        false => bits,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_packed_key!(
+    i8 => u32, false, true;
+    i16 => u32, false, true;
+    i32 => u32, false, true;
+    i64 => u64, true, true;
+    isize => u64, true, true;
+    u8 => u32, false, false;
+    u16 => u32, false, false;
+    u32 => u32, false, false;
+    u64 => u64, true, false;
+    usize => u64, true, false;
+);
+
+/// Emits the mapping of the probe in edi or rdi to its ordered bits, in place.
+pub(crate) fn emit_ordered_probe<K: PackedKey>(ops: &mut Emitter) {
+    match (K::SIGNED, K::WIDE) {
+        (true, true) => dynasm!(ops; btc rdi, 63),
+        (true, false) => dynasm!(ops; xor edi, i32::MIN),
+        _ => {}
+    }
+}
+
+/// Emits the comparison of the packed key at the index in `Rq(index)` of the array at r8
+/// against the ordered probe: carry is set if the key is less than the probe.
+pub(crate) fn emit_cmp_packed<K: PackedKey>(ops: &mut Emitter, index: u8) {
+    if K::WIDE {
+        dynasm!(ops; cmp QWORD [r8 + Rq(index) * 8], rdi);
+    } else {
+        dynasm!(ops; cmp DWORD [r8 + Rq(index) * 4], edi);
+    }
+}
+
+/// Returns the ordered bits of the keys, packed at their width.
+pub(crate) fn pack_keys<K: PackedKey>(ordered_bits: impl Iterator<Item = u64>) -> Vec<u8> {
+    let width = if K::WIDE { 8 } else { 4 };
+    ordered_bits
+        .flat_map(|bits| bits.to_le_bytes().into_iter().take(width))
+        .collect()
+}
 
-    // Recursively build the assembly from the tree structure
-    if let Some(node) = root {
-        build_asm(&mut ops, node, &mut labels, not_found_label);
+/// Minimum number of keys in a dense run for `compile` to emit a value table for it.
+pub const MIN_DENSE_RUN: usize = 16;
+
+/// Compiles `AvlTree::lookup` for the given tree.
+///
+/// Runs of at least `MIN_DENSE_RUN` keys spanning at most twice as many values, such as
+/// `0..n`, are looked up in a table of values indexed by the probe after a bounds check. The
+/// remaining keys, and the runs themselves, are then searched with a balanced comparison tree.
+pub fn compile<K: PackedKey, V: JitValue>(tree: &AvlTree<K, V>) -> CompiledLookup<K, V> {
+    let entries: Vec<(K, V)> = tree.iter().map(|(&key, &value)| (key, value)).collect();
+    let segments = dense_segments(&entries);
+
+    let mut ops = Emitter::new();
+
+    let start = ops.offset();
+    K::emit_load_probe(&mut ops);
+
+    let not_found_label = ops.new_dynamic_label();
+    if !segments.is_empty() {
+        build_segment_asm(&mut ops, &segments, not_found_label);
     }
 
-    // "Not found" block: move -1 into the return register (rax) and return
     dynasm!(ops
         ; =>not_found_label
-        ; mov rax, -1
-        ; ret
+        ; xor eax, eax
     );
+    ops.emit_ret();
 
-    // Finalize the buffer and cast it to a function pointer
-    let buf = ops.finalize().unwrap();
-    let func_ptr: JittedLookup = unsafe { std::mem::transmute(buf.ptr(start)) };
+    let buf = ops.finalize();
+    CompiledLookup::new(buf, start, tree.version())
+}
 
-    (buf, func_ptr)
+// Splits the sorted entries into dense runs and single entries, in order. Runs are grown
+// greedily while their keys span at most twice their number.
+fn dense_segments<K: PackedKey, V>(entries: &[(K, V)]) -> Vec<&[(K, V)]> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    while start < entries.len() {
+        let lo = entries[start].0.ordered_bits();
+        let mut end = start + 1;
+        while end < entries.len()
+            && (entries[end].0.ordered_bits() - lo) / 2 < (end + 1 - start) as u64
+        {
+            end += 1;
+        }
+        if end - start < MIN_DENSE_RUN {
+            end = start + 1;
+        }
+        segments.push(&entries[start..end]);
+        start = end;
+    }
+    segments
 }
 
-// Recursive helper to generate assembly for a subtree
-fn build_asm(
-    ops: &mut dynasmrt::x64::Assembler,
-    node: &Node<i32, i32>,
-    labels: &mut HashMap<i32, dynasmrt::DynamicLabel>,
-    not_found_label: dynasmrt::DynamicLabel,
+// Recursive helper to generate a balanced comparison tree over the segments, in pre-order: the
+// middle segment's block, followed by the blocks of the segments below and above it.
+fn build_segment_asm<K: PackedKey, V: JitValue>(
+    ops: &mut Emitter,
+    segments: &[&[(K, V)]],
+    not_found_label: DynamicLabel,
 ) {
-    // Get or create a label for the current node
-    let self_label = *labels
-        .entry(node.key)
-        .or_insert_with(|| ops.new_dynamic_label());
-    let found_label = ops.new_dynamic_label();
+    let mid = segments.len() / 2;
+    let (below, above) = (&segments[..mid], &segments[mid + 1..]);
+    let below_label = segment_label(ops, below, not_found_label);
+    let above_label = segment_label(ops, above, not_found_label);
+
+    match segments[mid] {
+        [(key, value)] => {
+            key.emit_compare(ops, below_label, above_label);
+            codegen::emit_found(ops, value.to_bits());
+        }
+        run => emit_value_table(ops, run, below_label, above_label),
+    }
 
-    // Define the entry point for this node's logic
-    dynasm!(ops; =>self_label);
+    for (segments, label) in [(below, below_label), (above, above_label)] {
+        if !segments.is_empty() {
+            dynasm!(ops; =>label);
+            build_segment_asm(ops, segments, not_found_label);
+        }
+    }
+}
+
+// Returns a fresh label for the code block of `segments`, or `missing` if there are none
+fn segment_label<T>(ops: &mut Emitter, segments: &[T], missing: DynamicLabel) -> DynamicLabel {
+    match segments {
+        [] => missing,
+        _ => ops.new_dynamic_label(),
+    }
+}
 
-    // Compare the input key (in rdi) with the node's key
+// Emits the bounds check of a dense run against its first and last keys, then the load of the
+// value at the offset of the probe from the first key. Runs without holes need only the values,
+// others have entries laid out like `RawLookup`.
+fn emit_value_table<K: PackedKey, V: JitValue>(
+    ops: &mut Emitter,
+    run: &[(K, V)],
+    less: DynamicLabel,
+    greater: DynamicLabel,
+) {
+    let (first, last) = (run[0].0, run[run.len() - 1].0);
+    let lo = first.ordered_bits();
+    let span = (last.ordered_bits() - lo) as usize + 1;
+    let above_first = ops.new_dynamic_label();
+    let table = ops.new_dynamic_label();
+
+    first.emit_compare(ops, less, above_first);
     dynasm!(ops
-        ; cmp edi, node.key as i32 // Use edi for 32-bit comparison
-        ; je =>found_label
+        ; jmp =>table
+        ; =>above_first
     );
+    last.emit_compare(ops, table, greater);
+    dynasm!(ops; =>table);
 
-    // Decide which child to go to, or jump to "not_found"
-    if let Some(left) = &node.left {
-        let left_label = *labels
-            .entry(left.key)
-            .or_insert_with(|| ops.new_dynamic_label());
-        dynasm!(ops; jl =>left_label);
+    // Offsets between keys are the same for their ordered bits
+    emit_ordered_probe::<K>(ops);
+    if !K::WIDE {
+        dynasm!(ops
+            ; mov eax, edi
+            ; sub eax, lo as i32
+        );
+    } else if let Ok(lo) = i32::try_from(lo as i64) {
+        dynasm!(ops
+            ; mov rax, rdi
+            ; sub rax, lo
+        );
     } else {
-        dynasm!(ops; jl =>not_found_label);
+        dynasm!(ops
+            ; mov rcx, QWORD lo as i64
+            ; mov rax, rdi
+            ; sub rax, rcx
+        );
     }
 
-    if let Some(right) = &node.right {
-        let right_label = *labels
-            .entry(right.key)
-            .or_insert_with(|| ops.new_dynamic_label());
-        dynasm!(ops; jg =>right_label);
+    if span == run.len() {
+        let values: Vec<u8> = run
+            .iter()
+            .flat_map(|(_, value)| value.to_bits().to_le_bytes())
+            .collect();
+        let values = ops.constant(&values, 8);
+        dynasm!(ops
+            ; lea rcx, [=>values]
+            ; mov rdx, QWORD [rcx + rax * 8]
+            ; mov eax, 1
+        );
     } else {
-        dynasm!(ops; jg =>not_found_label);
+        let mut entries = vec![0u8; span * 16];
+        for (key, value) in run {
+            let offset = (key.ordered_bits() - lo) as usize * 16;
+            entries[offset..offset + 8].copy_from_slice(&1u64.to_le_bytes());
+            entries[offset + 8..offset + 16].copy_from_slice(&value.to_bits().to_le_bytes());
+        }
+        let entries = ops.constant(&entries, 16);
+        dynasm!(ops
+            ; lea rcx, [=>entries]
+            ; shl rax, 4
+            ; mov rdx, QWORD [rcx + rax + 8]
+            ; mov rax, QWORD [rcx + rax]
+        );
+    }
+    ops.emit_ret();
+}
+
+/// Compiles `AvlTree::rank` for the given tree.
+pub fn compile_rank<K: IntKey, V>(tree: &AvlTree<K, V>) -> CompiledRank<K> {
+    codegen::compile_rank(tree)
+}
+
+/// Compiles the given neighbor query (`AvlTree::floor` and friends) for the given tree.
+pub fn compile_neighbor<V: JitValue>(
+    tree: &AvlTree<i32, V>,
+    kind: Neighbor,
+) -> CompiledNeighbor<i32, V> {
+    codegen::compile_neighbor_with(tree, kind, &KeyOps::scalar(), emit_neighbor_key)
+}
+
+// Writes the key to the out pointer (in rsi)
+fn emit_neighbor_key(ops: &mut Emitter, key: &i32) {
+    dynasm!(ops; mov DWORD [rsi], *key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, NEIGHBORS, expected_neighbor, random_tree};
+    use rand::prelude::*;
+    use std::fmt::Debug;
+
+    // Checks `compile` and `compile_rank` against the tree, for trees with and without dense runs
+    fn check_table_jit<K: PackedKey + Debug>(tree: &AvlTree<K, u64>, probes: &[K]) {
+        let compiled = compile(tree);
+        let compiled_rank = compile_rank(tree);
+        test_util::check_against_tree(
+            tree,
+            probes,
+            |probe| compiled.lookup(probe),
+            Some(&compiled_rank),
+        );
     }
 
-    // Recursively build assembly for children. Pre-order traversal is natural here.
-    if let Some(left) = &node.left {
-        build_asm(ops, left, labels, not_found_label);
+    #[test]
+    fn test_dense_segments() {
+        let keys: Vec<(i32, ())> = (0..20)
+            .chain([100, 200])
+            .chain((300..340).step_by(2))
+            .chain((1000..1040).step_by(3))
+            .map(|k| (k, ()))
+            .collect();
+        let lens: Vec<usize> = dense_segments(&keys).iter().map(|s| s.len()).collect();
+        // A contiguous run, two sparse keys, a run with holes, and keys too far apart
+        assert_eq!(lens[..4], [20, 1, 1, 20]);
+        assert!(lens[4..].iter().all(|&len| len == 1));
+        assert_eq!(lens.len(), 4 + (1000..1040).step_by(3).count());
     }
-    if let Some(right) = &node.right {
-        build_asm(ops, right, labels, not_found_label);
+
+    #[test]
+    fn test_dense_runs_with_sparse_regions() {
+        let mut rng = StdRng::seed_from_u64(2323);
+        let mut tree = AvlTree::new();
+        for k in (-50..50).chain(1000..1100).chain((5000..6000).step_by(2)) {
+            tree.insert(k, rng.random::<u64>());
+        }
+        for _ in 0..200 {
+            tree.insert(rng.random::<i32>(), rng.random::<u64>());
+        }
+        // Runs at the ends of the key range
+        for k in (i32::MIN..i32::MIN + 40).chain(i32::MAX - 40..=i32::MAX) {
+            tree.insert(k, k as u64);
+        }
+        let mut probes: Vec<i32> = (-100..7000).collect();
+        probes.extend([i32::MIN, i32::MIN + 40, i32::MAX - 41, i32::MAX]);
+        probes.extend((0..1000).map(|_| rng.random::<i32>()));
+        check_table_jit(&tree, &probes);
     }
 
-    // If we jumped here, it means the key was equal.
-    // Move the node's value into the return register and return.
-    dynasm!(ops
-        ; =>found_label
-        ; mov rax, node.value as i32
-        ; ret
-    );
+    #[test]
+    fn test_dense_runs_of_every_width() {
+        let tree: AvlTree<u8, u64> = (0..=255).map(|k| (k, k as u64)).collect();
+        check_table_jit(&tree, &[]);
+        let tree: AvlTree<i8, u64> = (-128..=127).step_by(2).map(|k| (k, k as u64)).collect();
+        check_table_jit(&tree, &[-127, 0, 1, 127]);
+
+        let mut probes = vec![0, 1, u64::MAX];
+        probes.extend((0..100).map(|k| (1 << 40) + k));
+        let tree: AvlTree<u64, u64> = (0..64)
+            .map(|k| ((1 << 40) + k, k))
+            .chain((0..32).map(|k| (u64::MAX - k, k)))
+            .collect();
+        check_table_jit(&tree, &probes);
+        let tree: AvlTree<i64, u64> = (-1000..1000).map(|k| (k * 1_000_000_007, 0)).collect();
+        check_table_jit(&tree, &[0, 1, -1_000_000_007]);
+        let tree: AvlTree<i64, u64> = (i64::MIN..i64::MIN + 100).map(|k| (k, 7)).collect();
+        check_table_jit(&tree, &[i64::MIN + 100, i64::MAX]);
+    }
+
+    #[test]
+    fn test_empty_and_single_key_trees() {
+        check_table_jit(&AvlTree::<i32, u64>::new(), &[0, 1]);
+        check_table_jit(
+            &[(5, 9)].into_iter().collect::<AvlTree<i32, u64>>(),
+            &[4, 6],
+        );
+    }
+
+    #[test]
+    fn test_i32_jit_correctness() {
+        let tree_size = 1000;
+        let lookups = 10000;
+        let seed = 12345;
+
+        let mut tree = AvlTree::new();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut keys: Vec<i32> = (0..tree_size).collect();
+        keys.shuffle(&mut rng);
+        for &key in &keys {
+            tree.insert(key, key);
+        }
+
+        let mut lookup_keys = Vec::with_capacity(lookups as usize);
+        for _ in 0..lookups {
+            lookup_keys.push(rng.random_range(0..tree_size));
+        }
+
+        let mut generic_correct_count = 0;
+        for &key in &lookup_keys {
+            if tree.lookup(&key).is_some() {
+                generic_correct_count += 1;
+            }
+        }
+
+        let compiled = compile(&tree);
+        let mut jit_correct_count = 0;
+        for &key in &lookup_keys {
+            if compiled.lookup(&key).is_some() {
+                jit_correct_count += 1;
+            }
+        }
+
+        assert_eq!(
+            generic_correct_count, jit_correct_count,
+            "Mismatch in i32 JIT correctness"
+        );
+    }
+
+    #[test]
+    fn test_u32_jit_correctness() {
+        let mut rng = StdRng::seed_from_u64(32);
+        let boundaries = [
+            0,
+            1,
+            i32::MAX as u32,
+            i32::MAX as u32 + 1,
+            u32::MAX - 1,
+            u32::MAX,
+        ];
+        let (tree, probes) = random_tree(1000, &boundaries, || rng.random::<u32>());
+        check_table_jit(&tree, &probes);
+    }
+
+    #[test]
+    fn test_u64_jit_correctness() {
+        let mut rng = StdRng::seed_from_u64(64);
+        // Small keys fit in a sign-extended imm32, others need a register
+        let random_key = || match rng.random_bool(0.5) {
+            true => rng.random::<u64>(),
+            false => rng.random_range(0..1000),
+        };
+        let boundaries = [
+            0,
+            1,
+            i32::MAX as u64,
+            i32::MAX as u64 + 1,
+            u32::MAX as u64,
+            u32::MAX as u64 + 1,
+            i64::MAX as u64,
+            i64::MAX as u64 + 1,
+            u64::MAX - 1,
+            u64::MAX,
+        ];
+        let (tree, probes) = random_tree(1000, &boundaries, random_key);
+        check_table_jit(&tree, &probes);
+    }
+
+    #[test]
+    fn test_i64_jit_correctness() {
+        let mut rng = StdRng::seed_from_u64(-64i64 as u64);
+        let random_key = || match rng.random_bool(0.5) {
+            true => rng.random::<i64>(),
+            false => rng.random_range(-1000..1000),
+        };
+        let boundaries = [
+            i64::MIN,
+            i64::MIN + 1,
+            i32::MIN as i64 - 1,
+            i32::MIN as i64,
+            -1,
+            0,
+            i32::MAX as i64,
+            i32::MAX as i64 + 1,
+            i64::MAX - 1,
+            i64::MAX,
+        ];
+        let (tree, probes) = random_tree(1000, &boundaries, random_key);
+        check_table_jit(&tree, &probes);
+    }
+
+    #[test]
+    fn test_usize_jit_correctness() {
+        let mut rng = StdRng::seed_from_u64(usize::BITS as u64);
+        let random_key = || match rng.random_bool(0.5) {
+            true => rng.random::<u64>() as usize,
+            false => rng.random_range(0..1000),
+        };
+        let boundaries = [
+            0,
+            i32::MAX as usize + 1,
+            u32::MAX as usize + 1,
+            usize::MAX / 2 + 1,
+            usize::MAX - 1,
+            usize::MAX,
+        ];
+        let (tree, probes) = random_tree(1000, &boundaries, random_key);
+        check_table_jit(&tree, &probes);
+    }
+
+    #[test]
+    fn test_jit_returns_sentinel_like_values() {
+        // Values such as -1 used to be indistinguishable from a miss
+        let tree: AvlTree<i32, i32> = (-100..100).map(|k| (k, k)).collect();
+        let compiled = compile(&tree);
+        for key in -100..100 {
+            assert_eq!(compiled.lookup(&key), Some(key));
+        }
+        assert_eq!(compiled.lookup(&100), None);
+    }
+
+    #[test]
+    fn test_i32_jit_rank() {
+        let mut tree = AvlTree::new();
+        let mut rng = StdRng::seed_from_u64(8080);
+        let mut keys: Vec<i32> = (0..1000).map(|k| k * 2).collect();
+        keys.shuffle(&mut rng);
+        for &key in &keys {
+            tree.insert(key, key);
+        }
+
+        let compiled = compile_rank(&tree);
+        for probe in -5..2005 {
+            assert_eq!(compiled.rank(&probe), tree.rank(&probe), "rank of {probe}");
+        }
+        assert_eq!(compiled.rank(&i32::MIN), 0);
+        assert_eq!(compiled.rank(&i32::MAX), tree.len());
+        assert!(!compiled.is_stale(&tree));
+        tree.insert(1, 1);
+        assert!(compiled.is_stale(&tree));
+    }
+
+    #[test]
+    fn test_i32_jit_neighbors() {
+        let mut tree = AvlTree::new();
+        let mut rng = StdRng::seed_from_u64(1808);
+        for _ in 0..500 {
+            let key = rng.random_range(-1000..1000);
+            tree.insert(key, rng.random::<i32>());
+        }
+        check_neighbors(&tree, -1002..1002);
+    }
+
+    #[test]
+    fn test_jit_neighbors_of_every_value_width() {
+        // The values are stored at their offsets in `KeyValue<i32, V>`, at full width
+        let keys = (-100..100).step_by(3);
+        check_neighbors(&keys.clone().map(|k| (k, k as i8)).collect(), -102..102);
+        check_neighbors(&keys.clone().map(|k| (k, k as u16)).collect(), -102..102);
+        let tree: AvlTree<i32, u64> = keys
+            .clone()
+            .map(|k| (k, u64::MAX - (k + 100) as u64))
+            .collect();
+        check_neighbors(&tree, -102..102);
+        let tree: AvlTree<i32, i64> = keys.map(|k| (k, i64::MIN + (k + 100) as i64)).collect();
+        check_neighbors(&tree, -102..102);
+    }
+
+    fn check_neighbors<V: JitValue + PartialEq + Debug>(
+        tree: &AvlTree<i32, V>,
+        probes: std::ops::Range<i32>,
+    ) {
+        for kind in NEIGHBORS {
+            let compiled = compile_neighbor(tree, kind);
+            assert!(!compiled.is_stale(tree));
+            for probe in probes.clone() {
+                assert_eq!(
+                    compiled.neighbor(&probe),
+                    expected_neighbor(tree, &probe, kind),
+                    "{kind:?} of {probe}"
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,155 @@
+use crate::avl::AvlTree;
+use crate::backend::Backend;
+use crate::codegen::{self, Emitter, KeyOps};
+use crate::compiled::{CompiledLookup, CompiledRank, JitValue};
+
+use dynasmrt::{DynamicLabel, DynasmLabelApi, dynasm};
+
+/// Compiles `AvlTree::lookup` for the given tree using AVX2 comparisons of whole keys.
+///
+/// Panics if the CPU lacks AVX2 or BMI1, see `Backend::check`.
+pub fn compile_avx2<V: JitValue>(tree: &AvlTree<[u8; 32], V>) -> CompiledLookup<[u8; 32], V> {
+    codegen::compile_with(tree, &avx2_key_ops())
+}
+
+/// Compiles `AvlTree::rank` for the given tree using AVX2 comparisons of whole keys.
+pub fn compile_rank_avx2<V>(tree: &AvlTree<[u8; 32], V>) -> CompiledRank<[u8; 32]> {
+    codegen::compile_rank_with(tree, &avx2_key_ops())
+}
+
+/// Compiles `AvlTree::lookup` for the given tree using AVX-512 comparisons of whole keys.
+///
+/// Panics if the CPU lacks AVX512F, AVX512BW or BMI1, see `Backend::check`.
+pub fn compile_avx512<V: JitValue>(tree: &AvlTree<[u8; 64], V>) -> CompiledLookup<[u8; 64], V> {
+    codegen::compile_with(tree, &avx512_key_ops())
+}
+
+/// Compiles `AvlTree::rank` for the given tree using AVX-512 comparisons of whole keys.
+pub fn compile_rank_avx512<V>(tree: &AvlTree<[u8; 64], V>) -> CompiledRank<[u8; 64]> {
+    codegen::compile_rank_with(tree, &avx512_key_ops())
+}
+
+fn avx2_key_ops() -> KeyOps<[u8; 32]> {
+    Backend::Avx2.assert_supported();
+    KeyOps {
+        load_probe: load_probe_avx2,
+        compare: compare_avx2,
+    }
+}
+
+fn avx512_key_ops() -> KeyOps<[u8; 64]> {
+    Backend::Avx512.assert_supported();
+    KeyOps {
+        load_probe: load_probe_avx512,
+        compare: compare_avx512,
+    }
+}
+
+// dynasm has no EVEX encodings and rejects the ymm form of vpmovmskb, so these instructions
+// are emitted as raw bytes
+
+// vpmovmskb eax, ymm1
+const VPMOVMSKB_EAX_YMM1: [u8; 4] = [0xC5, 0xFD, 0xD7, 0xC1];
+// vmovdqu64 zmm0, [rdi]
+const VMOVDQU64_ZMM0_RDI: [u8; 6] = [0x62, 0xF1, 0xFE, 0x48, 0x6F, 0x07];
+// vpcmpb k1, zmm0, [rdx], 4 (not equal)
+const VPCMPNEQB_K1_ZMM0_RDX: [u8; 7] = [0x62, 0xF3, 0x7D, 0x48, 0x3F, 0x0A, 0x04];
+// kmovq rax, k1
+const KMOVQ_RAX_K1: [u8; 5] = [0xC4, 0xE1, 0xFB, 0x93, 0xC1];
+
+fn load_probe_avx2(ops: &mut Emitter) {
+    ops.use_wide_vectors();
+    dynasm!(ops; vmovdqu ymm0, [rdi]);
+}
+
+fn compare_avx2(key: &[u8; 32], ops: &mut Emitter, less: DynamicLabel, greater: DynamicLabel) {
+    let data = ops.constant(key, 32);
+    let equal = ops.new_dynamic_label();
+
+    // The inverted mask of equal bytes has a bit set for every differing byte
+    dynasm!(ops
+        ; lea rdx, [=>data]
+        ; vpcmpeqb ymm1, ymm0, [rdx]
+    );
+    ops.extend(VPMOVMSKB_EAX_YMM1);
+    dynasm!(ops
+        ; not eax
+        ; tzcnt eax, eax
+        ; jc =>equal
+    );
+    codegen::branch_first_difference(ops, less, greater);
+    dynasm!(ops; =>equal);
+}
+
+fn load_probe_avx512(ops: &mut Emitter) {
+    ops.use_wide_vectors();
+    ops.extend(VMOVDQU64_ZMM0_RDI);
+}
+
+fn compare_avx512(key: &[u8; 64], ops: &mut Emitter, less: DynamicLabel, greater: DynamicLabel) {
+    let data = ops.constant(key, 64);
+    let equal = ops.new_dynamic_label();
+
+    dynasm!(ops; lea rdx, [=>data]);
+    ops.extend(VPCMPNEQB_K1_ZMM0_RDX);
+    ops.extend(KMOVQ_RAX_K1);
+    dynasm!(ops
+        ; tzcnt rax, rax
+        ; jc =>equal
+    );
+    codegen::branch_first_difference(ops, less, greater);
+    dynasm!(ops; =>equal);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::KeyCodegen;
+    use crate::test_util::{alphabet_key, check_against_tree};
+    use rand::prelude::*;
+    use std::fmt::Debug;
+
+    type Compile<const N: usize> = fn(&AvlTree<[u8; N], usize>) -> CompiledLookup<[u8; N], usize>;
+    type CompileRank<const N: usize> = fn(&AvlTree<[u8; N], usize>) -> CompiledRank<[u8; N]>;
+
+    fn check_avx_jit<const N: usize>(seed: u64, compile: Compile<N>, compile_rank: CompileRank<N>)
+    where
+        [u8; N]: KeyCodegen<Arg = *const u8> + Debug,
+    {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut keys: Vec<[u8; N]> = (0..1000).map(|_| alphabet_key(&mut rng)).collect();
+        // Keys differing only in their last byte
+        keys.extend((0..=255u8).map(|b| {
+            let mut key = [0x80; N];
+            key[N - 1] = b;
+            key
+        }));
+        let tree: AvlTree<[u8; N], usize> = keys.iter().enumerate().map(|(i, &k)| (k, i)).collect();
+
+        let probes: Vec<[u8; N]> = (0..1000).map(|_| alphabet_key(&mut rng)).collect();
+        let compiled = compile(&tree);
+        let compiled_rank = compile_rank(&tree);
+        check_against_tree(
+            &tree,
+            &probes,
+            |probe| compiled.lookup(probe),
+            Some(&compiled_rank),
+        );
+    }
+
+    #[test]
+    fn test_avx2_jit_correctness() {
+        if !Backend::Avx2.is_supported() {
+            return;
+        }
+        check_avx_jit(3232, compile_avx2, compile_rank_avx2);
+    }
+
+    #[test]
+    fn test_avx512_jit_correctness() {
+        if !Backend::Avx512.is_supported() {
+            return;
+        }
+        check_avx_jit(6464, compile_avx512, compile_rank_avx512);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::avl::AvlTree;
+use crate::codegen::{self, EmitKey, Emitter, KeyCodegen};
+use crate::compiled::{CompiledLookup, CompiledRank, JitValue};
+
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi, dynasm};
+
+/// A byte string as passed to compiled code: its pointer in rdi and its length in rsi.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ByteSlice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl From<&[u8]> for ByteSlice {
+    fn from(bytes: &[u8]) -> Self {
+        ByteSlice {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+}
+
+// Node keys live in the constant pool and are compared against the probe by a shared
+// subroutine, which leaves the flags of an unsigned comparison of the probe against the node key.
+macro_rules! impl_bytes_key_codegen {
+    ($($ty:ty),*) => {
+        $(
+            impl KeyCodegen for $ty {
+                type Arg = ByteSlice;
+
+                fn arg(&self) -> ByteSlice {
+                    ByteSlice::from(&self[..])
+                }
+            }
+
+            impl EmitKey for $ty {
+                fn emit_compare(
+                    &self,
+                    ops: &mut Emitter,
+                    less: DynamicLabel,
+                    greater: DynamicLabel,
+                ) {
+                    emit_compare_bytes(self, ops, less, greater);
+                }
+            }
+        )*
+    };
+}
+
+impl_bytes_key_codegen!(Vec<u8>, Box<[u8]>);
+
+fn emit_compare_bytes(key: &[u8], ops: &mut Emitter, less: DynamicLabel, greater: DynamicLabel) {
+    let data = ops.constant(key, 16);
+    let compare = ops.routine("compare_bytes", emit_compare_bytes_routine);
+    dynasm!(ops
+        ; lea r8, [=>data]
+        ; mov r9, QWORD key.len() as i64
+        ; call =>compare
+    );
+    codegen::branch_unsigned(ops, less, greater);
+}
+
+// Compares the probe (rdi, rsi) against the node key (r8, r9) like `Ord for [u8]`, returning
+// with the flags of an unsigned comparison: the first differing bytes decide, and if one key is
+// a prefix of the other, their lengths do.
+//
+// The common prefix is compared in 16-byte SSE chunks, then at most one qword and the remaining
+// bytes, so no load reaches past the end of either key.
+fn emit_compare_bytes_routine(ops: &mut Emitter) {
+    let chunk_loop = ops.new_dynamic_label();
+    let mismatch = ops.new_dynamic_label();
+    let tail = ops.new_dynamic_label();
+    let byte_loop = ops.new_dynamic_label();
+    let lengths = ops.new_dynamic_label();
+    let done = ops.new_dynamic_label();
+
+    dynasm!(ops
+        // rcx = length of the common prefix, rax = offset into both keys
+        ; mov rcx, rsi
+        ; cmp rcx, r9
+        ; cmova rcx, r9
+        ; xor eax, eax
+
+        ; =>chunk_loop
+        ; lea rdx, [rax + 16]
+        ; cmp rdx, rcx
+        ; ja =>tail
+        ; movdqu xmm0, [rdi + rax]
+        ; movdqu xmm1, [r8 + rax]
+        ; pcmpeqb xmm0, xmm1
+        ; pmovmskb edx, xmm0
+        ; xor edx, 0xFFFF
+        ; jnz =>mismatch
+        ; add rax, 16
+        ; jmp =>chunk_loop
+
+        // The mask has a bit set for every differing byte, the lowest one decides
+        ; =>mismatch
+        ; bsf edx, edx
+        ; add rax, rdx
+        ; movzx edx, BYTE [rdi + rax]
+        ; movzx r10d, BYTE [r8 + rax]
+        ; cmp edx, r10d
+        ; ret
+
+        // Less than 16 bytes left: compare a qword as big-endian integers if there is one
+        ; =>tail
+        ; lea rdx, [rax + 8]
+        ; cmp rdx, rcx
+        ; ja =>byte_loop
+        ; mov rdx, QWORD [rdi + rax]
+        ; mov r10, QWORD [r8 + rax]
+        ; bswap rdx
+        ; bswap r10
+        ; add rax, 8
+        ; cmp rdx, r10
+        ; jne =>done
+
+        ; =>byte_loop
+        ; cmp rax, rcx
+        ; je =>lengths
+        ; movzx edx, BYTE [rdi + rax]
+        ; movzx r10d, BYTE [r8 + rax]
+        ; add rax, 1
+        ; cmp edx, r10d
+        ; je =>byte_loop
+        ; =>done
+        ; ret
+
+        // The common prefix is equal, so the shorter key is the lesser one
+        ; =>lengths
+        ; cmp rsi, r9
+        ; ret
+    );
+}
+
+/// Compiles `AvlTree::lookup` for the given tree of byte string keys.
+pub fn compile<K, V>(tree: &AvlTree<K, V>) -> CompiledLookup<K, V>
+where
+    K: KeyCodegen<Arg = ByteSlice>,
+    V: JitValue,
+{
+    codegen::compile(tree)
+}
+
+/// Compiles `AvlTree::rank` for the given tree of byte string keys.
+pub fn compile_rank<K, V>(tree: &AvlTree<K, V>) -> CompiledRank<K>
+where
+    K: KeyCodegen<Arg = ByteSlice>,
+{
+    codegen::compile_rank(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{alphabet_bytes, check_against_tree};
+    use rand::prelude::*;
+
+    // Keys are often prefixes of each other, and lengths around the chunk sizes exercise every
+    // part of the comparison
+    fn random_key(rng: &mut StdRng) -> Vec<u8> {
+        let len = rng.random_range(0..=40);
+        alphabet_bytes(rng, len)
+    }
+
+    #[test]
+    fn test_bytes_jit_correctness() {
+        let mut rng = StdRng::seed_from_u64(4040);
+        let mut tree = AvlTree::new();
+        for i in 0..1000 {
+            tree.insert(random_key(&mut rng), i);
+        }
+        tree.insert(Vec::new(), -1);
+        // URL-like keys with a long common prefix
+        for i in 0..100 {
+            tree.insert(format!("https://example.com/items/{i}").into_bytes(), i);
+        }
+
+        let compiled = compile(&tree);
+        let compiled_rank = compile_rank(&tree);
+        let mut probes = Vec::new();
+        for key in tree.keys() {
+            // Proper prefixes and extensions of present keys
+            if let Some((_, prefix)) = key.split_last() {
+                probes.push(prefix.to_vec());
+            }
+            let mut extended = key.clone();
+            extended.push(0);
+            probes.push(extended);
+        }
+        probes.extend((0..1000).map(|_| random_key(&mut rng)));
+        check_against_tree(
+            &tree,
+            &probes,
+            |probe| compiled.lookup(probe),
+            Some(&compiled_rank),
+        );
+    }
+
+    #[test]
+    fn test_boxed_bytes_jit_correctness() {
+        let mut rng = StdRng::seed_from_u64(4041);
+        let keys: Vec<Box<[u8]>> = (0..500).map(|_| random_key(&mut rng).into()).collect();
+        let tree: AvlTree<Box<[u8]>, usize> = keys
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, k)| (k, i))
+            .collect();
+
+        let compiled = compile(&tree);
+        let probes: Vec<Box<[u8]>> = (0..500).map(|_| random_key(&mut rng).into()).collect();
+        check_against_tree(&tree, &probes, |probe| compiled.lookup(probe), None);
+        assert_eq!(
+            compile(&AvlTree::<Box<[u8]>, i32>::new()).lookup(&keys[0]),
+            None
+        );
+    }
+}
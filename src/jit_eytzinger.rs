@@ -0,0 +1,358 @@
+use crate::avl::AvlTree;
+use crate::codegen::Emitter;
+use crate::compiled::{CompiledBatchLookup, CompiledLookup, JitValue};
+use crate::jit::{self, PackedKey};
+
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi, dynasm};
+
+/// Compiles `AvlTree::lookup` for the given tree as a branchless search of its keys in
+/// Eytzinger (BFS) order.
+///
+/// The keys are padded to a complete tree, so every search takes exactly as many steps as the
+/// tree has levels, and the loop is unrolled to that count. Each step compares the probe to a
+/// key and moves to its left or right child with `adc`, prefetching the cache line of the
+/// descendants a few levels down. The code stays small however large the tree is.
+pub fn compile<K: PackedKey, V: JitValue>(tree: &AvlTree<K, V>) -> CompiledLookup<K, V> {
+    let mut ops = Emitter::new();
+    let tables = Tables::new(&mut ops, tree);
+
+    let start = ops.offset();
+
+    // Map the probe to its ordered bits; rax is the slot, r8 points to the keys
+    jit::emit_ordered_probe::<K>(&mut ops);
+    dynasm!(ops
+        ; lea r8, [=>tables.keys]
+        ; mov eax, 1
+    );
+
+    for level in 0..tables.levels {
+        if tables.prefetch::<K>(level) {
+            dynasm!(ops
+                ; mov rcx, rax
+                ; shl rcx, 6
+                ; prefetcht0 [r8 + rcx]
+            );
+        }
+        // Carry is set if the key is less than the probe, which then goes right
+        jit::emit_cmp_packed::<K>(&mut ops, 0);
+        dynasm!(ops; adc rax, rax);
+    }
+
+    // Strip the trailing right turns and the last left turn to get the slot of the smallest
+    // key not less than the probe, then return its entry if that key is the probe
+    dynasm!(ops
+        ; mov rcx, rax
+        ; not rcx
+        ; bsf rcx, rcx
+        ; inc ecx
+        ; shr rax, cl
+        ; lea r9, [=>tables.entries]
+        ; mov rcx, rax
+        ; shl rcx, 4
+        ; mov rdx, QWORD [r9 + rcx + 8]
+        ; mov rcx, QWORD [r9 + rcx]
+    );
+    jit::emit_cmp_packed::<K>(&mut ops, 0);
+    dynasm!(ops
+        ; mov eax, 0
+        ; cmove rax, rcx
+    );
+    ops.emit_ret();
+
+    let buf = ops.finalize();
+    CompiledLookup::new(buf, start, tree.version())
+}
+
+// The searches of a batch that run interleaved, one slot register each
+const BATCH_LANES: usize = 8;
+const LANE_SLOTS: [u8; BATCH_LANES] = [0, 10, 11, 3, 5, 12, 13, 15];
+
+/// Compiles a batched `AvlTree::lookup` for the given tree, searching the same Eytzinger layout
+/// as `compile`.
+///
+/// The keys of a batch are searched eight at a time, level by level in lockstep, so that the
+/// cache misses of the independent searches overlap instead of following one another (group
+/// prefetching). Batches of thousands of keys thus take a fraction of the time of looking up
+/// their keys one by one.
+pub fn compile_batch<K: PackedKey, V: JitValue>(tree: &AvlTree<K, V>) -> CompiledBatchLookup<K, V> {
+    let mut ops = Emitter::new();
+    let tables = Tables::new(&mut ops, tree);
+    let group_loop = ops.new_dynamic_label();
+    let single_loop = ops.new_dynamic_label();
+    let done = ops.new_dynamic_label();
+    let key_size = std::mem::size_of::<K>() as i32;
+
+    let start = ops.offset();
+
+    // rdi points to the keys, rsi counts those left, and rdx points to the results. The ordered
+    // probes of a group are kept on the stack, rcx and r14 are scratch.
+    dynasm!(ops
+        ; push rbx
+        ; push rbp
+        ; push r12
+        ; push r13
+        ; push r14
+        ; push r15
+        ; sub rsp, BATCH_LANES as i32 * 8
+        ; lea r8, [=>tables.keys]
+        ; lea r9, [=>tables.entries]
+
+        ; =>group_loop
+        ; cmp rsi, BATCH_LANES as i32
+        ; jb =>single_loop
+    );
+    emit_batch_group::<K>(&mut ops, &tables, &LANE_SLOTS);
+    dynasm!(ops
+        ; add rdi, key_size * BATCH_LANES as i32
+        ; add rdx, 16 * BATCH_LANES as i32
+        ; sub rsi, BATCH_LANES as i32
+        ; jmp =>group_loop
+
+        // The remaining keys are searched one at a time
+        ; =>single_loop
+        ; test rsi, rsi
+        ; jz =>done
+    );
+    emit_batch_group::<K>(&mut ops, &tables, &LANE_SLOTS[..1]);
+    dynasm!(ops
+        ; add rdi, key_size
+        ; add rdx, 16
+        ; dec rsi
+        ; jmp =>single_loop
+
+        ; =>done
+        ; add rsp, BATCH_LANES as i32 * 8
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbp
+        ; pop rbx
+    );
+    ops.emit_ret();
+
+    let buf = ops.finalize();
+    CompiledBatchLookup::new(buf, start, tree.version())
+}
+
+// Emits the searches of the keys at rdi, one per slot register in `slots`, writing their
+// results to rdx
+fn emit_batch_group<K: PackedKey>(ops: &mut Emitter, tables: &Tables, slots: &[u8]) {
+    for (lane, &slot) in slots.iter().enumerate() {
+        emit_load_ordered_key::<K>(ops, lane);
+        dynasm!(ops
+            ; mov [rsp + lane as i32 * 8], r14
+            ; mov Rd(slot), 1
+        );
+    }
+
+    for level in 0..tables.levels {
+        for (lane, &slot) in slots.iter().enumerate() {
+            if tables.prefetch::<K>(level) {
+                dynasm!(ops
+                    ; mov r14, Rq(slot)
+                    ; shl r14, 6
+                    ; prefetcht0 [r8 + r14]
+                );
+            }
+            emit_load_packed::<K>(ops, slot);
+            dynasm!(ops
+                ; cmp r14, [rsp + lane as i32 * 8]
+                ; adc Rq(slot), Rq(slot)
+            );
+        }
+    }
+
+    // As in `compile`, but writing the entry out
+    for (lane, &slot) in slots.iter().enumerate() {
+        let out = lane as i32 * 16;
+        dynasm!(ops
+            ; mov rcx, Rq(slot)
+            ; not rcx
+            ; bsf rcx, rcx
+            ; inc ecx
+            ; shr Rq(slot), cl
+            ; mov rcx, Rq(slot)
+            ; shl rcx, 4
+            ; mov r14, QWORD [r9 + rcx + 8]
+            ; mov [rdx + out + 8], r14
+            ; mov rcx, QWORD [r9 + rcx]
+        );
+        emit_load_packed::<K>(ops, slot);
+        dynasm!(ops
+            ; cmp r14, [rsp + lane as i32 * 8]
+            ; mov r14d, 0
+            ; cmovne rcx, r14
+            ; mov [rdx + out], rcx
+        );
+    }
+}
+
+// Loads the key of the given lane from rdi into r14, widened like its `KeyCodegen::Arg` and
+// mapped to its ordered bits
+fn emit_load_ordered_key<K: PackedKey>(ops: &mut Emitter, lane: usize) {
+    let offset = (lane * std::mem::size_of::<K>()) as i32;
+    match (std::mem::size_of::<K>(), K::SIGNED) {
+        (1, true) => dynasm!(ops; movsx r14d, BYTE [rdi + offset]),
+        (1, false) => dynasm!(ops; movzx r14d, BYTE [rdi + offset]),
+        (2, true) => dynasm!(ops; movsx r14d, WORD [rdi + offset]),
+        (2, false) => dynasm!(ops; movzx r14d, WORD [rdi + offset]),
+        (4, _) => dynasm!(ops; mov r14d, DWORD [rdi + offset]),
+        _ => dynasm!(ops; mov r14, QWORD [rdi + offset]),
+    }
+    match (K::SIGNED, K::WIDE) {
+        (true, true) => dynasm!(ops; btc r14, 63),
+        (true, false) => dynasm!(ops; xor r14d, i32::MIN),
+        _ => {}
+    }
+}
+
+// Loads the packed key at the slot in `Rq(slot)` of the array at r8 into r14, zero-extended
+fn emit_load_packed<K: PackedKey>(ops: &mut Emitter, slot: u8) {
+    if K::WIDE {
+        dynasm!(ops; mov r14, QWORD [r8 + Rq(slot) * 8]);
+    } else {
+        dynasm!(ops; mov r14d, DWORD [r8 + Rq(slot) * 4]);
+    }
+}
+
+// The keys and entries of a tree in Eytzinger order, in the constant pool
+struct Tables {
+    levels: u32,
+    keys: DynamicLabel,
+    entries: DynamicLabel,
+}
+
+impl Tables {
+    fn new<K: PackedKey, V: JitValue>(ops: &mut Emitter, tree: &AvlTree<K, V>) -> Self {
+        // Padding with the greatest key leaves the in-order sequence sorted, and the entries of
+        // the padding, like that of slot 0 (no key is greater or equal), are absent
+        let levels = usize::BITS - tree.len().leading_zeros();
+        let slots = 1 << levels;
+        let sorted: Vec<Option<(u64, u64)>> = tree
+            .iter()
+            .map(|(&key, value)| Some((key.ordered_bits(), value.to_bits())))
+            .chain(std::iter::repeat(None))
+            .take(slots - 1)
+            .collect();
+        let mut layout = vec![None; slots];
+        fill_eytzinger(&mut sorted.into_iter(), &mut layout, 1);
+
+        let pad_key = if K::WIDE { u64::MAX } else { u32::MAX as u64 };
+        let keys = jit::pack_keys::<K>(
+            layout
+                .iter()
+                .map(|slot| slot.map_or(pad_key, |(key, _)| key)),
+        );
+        // Entries are laid out like `RawLookup`: presence flag, then value bits
+        let mut entries = Vec::with_capacity(slots * 16);
+        for slot in &layout {
+            let (present, value) = slot.map_or((0, 0), |(_, value)| (1u64, value));
+            entries.extend_from_slice(&present.to_le_bytes());
+            entries.extend_from_slice(&value.to_le_bytes());
+        }
+
+        Tables {
+            levels,
+            keys: ops.constant(&keys, 64),
+            entries: ops.constant(&entries, 64),
+        }
+    }
+
+    // A cache line holds the keys of the descendants a few levels down, at slot
+    // `slot << distance`, which is 64 * slot bytes in for either key width. Returns whether to
+    // prefetch them at `level`.
+    fn prefetch<K: PackedKey>(&self, level: u32) -> bool {
+        let distance = if K::WIDE { 3 } else { 4 };
+        level + distance < self.levels
+    }
+}
+
+// Places the sorted entries at the slots of an in-order walk of the implicit tree rooted at
+// `slot`, whose children are at 2 * slot and 2 * slot + 1
+fn fill_eytzinger<T>(sorted: &mut impl Iterator<Item = T>, layout: &mut [T], slot: usize) {
+    if slot < layout.len() {
+        fill_eytzinger(sorted, layout, 2 * slot);
+        layout[slot] = sorted.next().unwrap();
+        fill_eytzinger(sorted, layout, 2 * slot + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{check_against_tree, random_tree};
+    use rand::distr::StandardUniform;
+    use rand::prelude::*;
+    use std::fmt::Debug;
+
+    fn check_eytzinger_jit<K>(seed: u64, boundaries: &[K])
+    where
+        K: PackedKey + Debug,
+        StandardUniform: Distribution<K>,
+    {
+        let mut rng = StdRng::seed_from_u64(seed);
+        // Sizes around powers of two exercise every amount of padding, for keys wide enough to
+        // rarely collide
+        for len in [0, 1, 2, 3, 4, 7, 8, 9, 100, 1000, 1023, 1024] {
+            let (tree, mut probes) = random_tree(len, boundaries, || rng.random::<K>());
+            let compiled = compile(&tree);
+            check_against_tree(&tree, &probes, |probe| compiled.lookup(probe), None);
+
+            let compiled_batch = compile_batch(&tree);
+            probes.extend(tree.keys());
+            let mut batch = vec![None; probes.len()];
+            compiled_batch.lookup_batch(&probes, &mut batch);
+            for (probe, batched) in probes.iter().zip(batch) {
+                assert_eq!(
+                    batched,
+                    tree.lookup(probe),
+                    "batched lookup of {probe:?} among {len} keys"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_eytzinger_jit_correctness() {
+        check_eytzinger_jit::<i32>(1, &[i32::MIN, -1, 0, 1, i32::MAX]);
+        check_eytzinger_jit::<u32>(2, &[0, 1, i32::MAX as u32 + 1, u32::MAX - 1, u32::MAX]);
+        check_eytzinger_jit::<i64>(3, &[i64::MIN, -1, 0, 1, i64::MAX]);
+        check_eytzinger_jit::<u64>(4, &[0, 1, i64::MAX as u64 + 1, u64::MAX - 1, u64::MAX]);
+        check_eytzinger_jit::<i8>(5, &[i8::MIN, -1, 0, 1, i8::MAX]);
+        check_eytzinger_jit::<u16>(6, &[0, 1, u16::MAX - 1, u16::MAX]);
+        check_eytzinger_jit::<i16>(7, &[i16::MIN, -1, 0, 1, i16::MAX]);
+        check_eytzinger_jit::<u8>(8, &[0, 1, 127, 128, u8::MAX]);
+    }
+
+    #[test]
+    fn test_eytzinger_batch_sizes() {
+        let tree: AvlTree<i32, i32> = (0..1000).map(|k| (k * 3, -k)).collect();
+        let compiled = compile_batch(&tree);
+        // Full groups, partial groups, and more keys than a chunk of results
+        for n in [0, 1, 7, 8, 9, 17, 300, 1000] {
+            let keys: Vec<i32> = (0..n).map(|i| i * 7 - 5).collect();
+            let mut out = vec![Some(1); n as usize + 1];
+            compiled.lookup_batch(&keys, &mut out);
+            for (key, value) in keys.iter().zip(&out) {
+                assert_eq!(
+                    *value,
+                    tree.lookup(key),
+                    "lookup of {key} in a batch of {n}"
+                );
+            }
+            // Results past the keys are left alone
+            assert_eq!(out[n as usize], Some(1));
+        }
+    }
+
+    #[test]
+    fn test_eytzinger_jit_sparse_keys() {
+        let tree: AvlTree<i32, i32> = (0..1000).map(|k| (k * 1000, -k)).collect();
+        let compiled = compile(&tree);
+        assert_eq!(compiled.lookup(&0), Some(0));
+        assert_eq!(compiled.lookup(&-1), None);
+        assert_eq!(compiled.lookup(&999_000), Some(-999));
+        assert_eq!(compiled.lookup(&999_001), None);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::avl::AvlTree;
+use crate::codegen::{self, EmitKey, Emitter, KeyCodegen};
+use crate::compiled::{CompiledLookup, CompiledRank, JitValue};
+
+use dynasmrt::DynamicLabel;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+
+/// An `f64` key ordered by `f64::total_cmp`.
+///
+/// Unlike `f64`, this is a total order and so usable as an `AvlTree` key: -0.0 sorts right
+/// before 0.0, and NaNs sort below -inf or above +inf depending on their sign bit. Keys are equal
+/// only if their bits are, so -0.0 and 0.0 are distinct keys, and so are NaNs with different
+/// payloads.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OrdF64(pub f64);
+
+impl OrdF64 {
+    /// Returns the bits of the value as an integer whose signed order is `f64::total_cmp`.
+    pub fn total_order_bits(self) -> i64 {
+        let bits = self.0.to_bits() as i64;
+        // Flipping all but the sign bit of negative values reverses their order, so that larger
+        // magnitudes sort lower
+        bits ^ (((bits >> 63) as u64) >> 1) as i64
+    }
+}
+
+impl From<f64> for OrdF64 {
+    fn from(value: f64) -> Self {
+        OrdF64(value)
+    }
+}
+
+impl PartialEq for OrdF64 {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for OrdF64 {}
+
+impl PartialOrd for OrdF64 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrdF64 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl Hash for OrdF64 {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+// The probe is mapped to its total order bits on the way in, so the compiled code is the
+// signed 64-bit integer comparison, against the total order bits of the node keys.
+impl KeyCodegen for OrdF64 {
+    type Arg = i64;
+
+    fn arg(&self) -> i64 {
+        self.total_order_bits()
+    }
+}
+
+impl EmitKey for OrdF64 {
+    fn emit_compare(&self, ops: &mut Emitter, less: DynamicLabel, greater: DynamicLabel) {
+        self.total_order_bits().emit_compare(ops, less, greater);
+    }
+}
+
+/// Compiles `AvlTree::lookup` for the given tree.
+pub fn compile<V: JitValue>(tree: &AvlTree<OrdF64, V>) -> CompiledLookup<OrdF64, V> {
+    codegen::compile(tree)
+}
+
+/// Compiles `AvlTree::rank` for the given tree. The compiled code takes the total order bits of
+/// the probe, see `OrdF64::total_order_bits`.
+pub fn compile_rank<V>(tree: &AvlTree<OrdF64, V>) -> CompiledRank<OrdF64> {
+    codegen::compile_rank(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::check_against_tree;
+    use rand::prelude::*;
+
+    // Values whose order trips up comparisons other than `total_cmp`
+    fn special_values() -> Vec<f64> {
+        vec![
+            0.0,
+            -0.0,
+            f64::NAN,
+            -f64::NAN,
+            f64::from_bits(f64::NAN.to_bits() | 1),
+            f64::from_bits((-f64::NAN).to_bits() | 1),
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::MIN,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            -f64::MIN_POSITIVE,
+            f64::from_bits(1),
+            -f64::from_bits(1),
+            f64::EPSILON,
+            1.0,
+            -1.0,
+        ]
+    }
+
+    #[test]
+    fn test_total_order_bits_follow_total_cmp() {
+        let mut rng = StdRng::seed_from_u64(754);
+        let mut values = special_values();
+        values.extend((0..200).map(|_| f64::from_bits(rng.random())));
+        for &a in &values {
+            for &b in &values {
+                assert_eq!(
+                    OrdF64(a)
+                        .total_order_bits()
+                        .cmp(&OrdF64(b).total_order_bits()),
+                    a.total_cmp(&b),
+                    "{a:?} vs {b:?}"
+                );
+                assert_eq!(OrdF64(a) == OrdF64(b), a.to_bits() == b.to_bits());
+            }
+        }
+    }
+
+    #[test]
+    fn test_f64_jit_correctness() {
+        let mut rng = StdRng::seed_from_u64(1754);
+        let mut tree = AvlTree::new();
+        for _ in 0..1000 {
+            tree.insert(OrdF64(rng.random_range(-1e6..1e6)), rng.random::<i32>());
+        }
+        // Every other special value is present, the rest are only probed
+        let specials = special_values();
+        for (i, &value) in specials.iter().enumerate().step_by(2) {
+            tree.insert(OrdF64(value), i as i32);
+        }
+
+        let compiled = compile(&tree);
+        let compiled_rank = compile_rank(&tree);
+        let mut probes: Vec<OrdF64> = specials.into_iter().map(OrdF64).collect();
+        probes.extend((0..1000).map(|_| OrdF64(f64::from_bits(rng.random()))));
+        check_against_tree(
+            &tree,
+            &probes,
+            |probe| compiled.lookup(probe),
+            Some(&compiled_rank),
+        );
+    }
+
+    #[test]
+    fn test_f64_jit_zero_and_nan_are_deterministic() {
+        let mut tree = AvlTree::new();
+        tree.insert(OrdF64(0.0), 1);
+        tree.insert(OrdF64(f64::NAN), 2);
+        let compiled = compile(&tree);
+
+        assert_eq!(compiled.lookup(&OrdF64(0.0)), Some(1));
+        assert_eq!(compiled.lookup(&OrdF64(-0.0)), None);
+        assert_eq!(compiled.lookup(&OrdF64(f64::NAN)), Some(2));
+        assert_eq!(compiled.lookup(&OrdF64(-f64::NAN)), None);
+        let compiled_rank = compile_rank(&tree);
+        // -0.0 < 0.0 < +inf < NaN
+        assert_eq!(compiled_rank.rank(&OrdF64(-0.0)), 0);
+        assert_eq!(compiled_rank.rank(&OrdF64(f64::INFINITY)), 1);
+        assert_eq!(compiled_rank.rank(&OrdF64(-f64::NAN)), 0);
+    }
+}
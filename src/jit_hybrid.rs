@@ -0,0 +1,225 @@
+use crate::avl::{AvlTree, Node};
+use crate::codegen::{self, Emitter};
+use crate::compiled::{CompiledLookup, JitValue};
+use crate::jit::{self, PackedKey};
+
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi, dynasm};
+
+/// Size of the L1 instruction cache assumed by `default_levels`.
+pub const L1I_CACHE_BYTES: usize = 32 * 1024;
+
+// Rough size of a node block: compare, two branches and the "found" epilogue
+fn node_bytes<K: PackedKey>() -> usize {
+    if K::WIDE { 48 } else { 32 }
+}
+
+/// Returns the number of tree levels whose node blocks fit in half of an instruction cache of
+/// `icache_bytes`, leaving the other half to the caller.
+pub fn levels_for_icache<K: PackedKey>(icache_bytes: usize) -> u32 {
+    (icache_bytes / 2 / node_bytes::<K>() + 1).ilog2()
+}
+
+/// Returns the number of levels `compile` emits node blocks for, see `levels_for_icache`.
+pub fn default_levels<K: PackedKey>() -> u32 {
+    levels_for_icache::<K>(L1I_CACHE_BYTES)
+}
+
+/// Compiles `AvlTree::lookup` for the given tree, with node blocks for the top
+/// `default_levels` levels, see `compile_with_levels`.
+pub fn compile<K: PackedKey, V: JitValue>(tree: &AvlTree<K, V>) -> CompiledLookup<K, V> {
+    compile_with_levels(tree, default_levels::<K>())
+}
+
+/// Compiles `AvlTree::lookup` for the given tree, with compare-and-branch blocks for the nodes
+/// of the top `levels` levels only.
+///
+/// The subtrees below are packed into sorted arrays of keys and values, which one shared block
+/// binary searches without branching on the keys. The code of large trees thus stays within
+/// the instruction cache, while the levels searched most often keep their specialized code.
+pub fn compile_with_levels<K: PackedKey, V: JitValue>(
+    tree: &AvlTree<K, V>,
+    levels: u32,
+) -> CompiledLookup<K, V> {
+    let mut ops = Emitter::new();
+
+    let start = ops.offset();
+    K::emit_load_probe(&mut ops);
+
+    let not_found_label = ops.new_dynamic_label();
+    let search_label = ops.new_dynamic_label();
+    let mut packed = Vec::new();
+
+    if let Some(node) = &tree.root {
+        build_hybrid_asm(
+            &mut ops,
+            node,
+            levels,
+            not_found_label,
+            search_label,
+            &mut packed,
+        );
+    }
+
+    dynasm!(ops
+        ; =>not_found_label
+        ; xor eax, eax
+    );
+    ops.emit_ret();
+
+    if !packed.is_empty() {
+        emit_search::<K>(&mut ops, search_label, &packed);
+    }
+
+    let buf = ops.finalize();
+    CompiledLookup::new(buf, start, tree.version())
+}
+
+// Recursive helper to generate the node blocks of the top `levels` levels of a subtree, in
+// pre-order like `codegen::compile`. A subtree reached below them is appended to `packed` as
+// (ordered key bits, value bits) and searched from its offset there.
+fn build_hybrid_asm<K: PackedKey, V: JitValue>(
+    ops: &mut Emitter,
+    node: &Node<K, V>,
+    levels: u32,
+    not_found_label: DynamicLabel,
+    search_label: DynamicLabel,
+    packed: &mut Vec<(u64, u64)>,
+) {
+    if levels == 0 {
+        let offset = packed.len();
+        pack_in_order(node, packed);
+        // The search block takes the offset of the slice in rax and its length in rcx
+        dynasm!(ops
+            ; mov eax, offset as i32
+            ; mov ecx, (packed.len() - offset) as i32
+            ; jmp =>search_label
+        );
+        return;
+    }
+
+    let left_label = codegen::child_label(ops, &node.left, not_found_label);
+    let right_label = codegen::child_label(ops, &node.right, not_found_label);
+
+    node.key.emit_compare(ops, left_label, right_label);
+    codegen::emit_found(ops, node.value.to_bits());
+
+    for (child, label) in [(&node.left, left_label), (&node.right, right_label)] {
+        if let Some(child) = child {
+            dynasm!(ops; =>label);
+            build_hybrid_asm(
+                ops,
+                child,
+                levels - 1,
+                not_found_label,
+                search_label,
+                packed,
+            );
+        }
+    }
+}
+
+fn pack_in_order<K: PackedKey, V: JitValue>(node: &Node<K, V>, packed: &mut Vec<(u64, u64)>) {
+    if let Some(left) = &node.left {
+        pack_in_order(left, packed);
+    }
+    packed.push((node.key.ordered_bits(), node.value.to_bits()));
+    if let Some(right) = &node.right {
+        pack_in_order(right, packed);
+    }
+}
+
+// Emits the binary search of the slice of `packed` at offset rax and of length rcx, which is
+// never empty. Each step halves the slice, moving its start up with `cmov` if the middle key is
+// not greater than the probe, so the loop runs a fixed number of times for a given length.
+fn emit_search<K: PackedKey>(ops: &mut Emitter, search_label: DynamicLabel, packed: &[(u64, u64)]) {
+    let keys = jit::pack_keys::<K>(packed.iter().map(|&(key, _)| key));
+    let values: Vec<u8> = packed
+        .iter()
+        .flat_map(|&(_, value)| value.to_le_bytes())
+        .collect();
+    let keys = ops.constant(&keys, 64);
+    let values = ops.constant(&values, 64);
+    let search_loop = ops.new_dynamic_label();
+    let done = ops.new_dynamic_label();
+
+    dynasm!(ops; =>search_label);
+    jit::emit_ordered_probe::<K>(ops);
+    dynasm!(ops
+        ; lea r8, [=>keys]
+        ; =>search_loop
+        ; cmp rcx, 1
+        ; jbe =>done
+        ; mov rdx, rcx
+        ; shr rdx, 1
+        ; lea r11, [rax + rdx]
+    );
+    jit::emit_cmp_packed::<K>(ops, 11);
+    dynasm!(ops
+        ; cmovbe rax, r11
+        ; sub rcx, rdx
+        ; jmp =>search_loop
+
+        // rax is the last key not greater than the probe, or the first of the slice
+        ; =>done
+        ; lea r9, [=>values]
+        ; mov rdx, QWORD [r9 + rax * 8]
+        ; mov ecx, 1
+    );
+    jit::emit_cmp_packed::<K>(ops, 0);
+    dynasm!(ops
+        ; mov eax, 0
+        ; cmove rax, rcx
+    );
+    ops.emit_ret();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{check_against_tree, random_tree};
+    use rand::distr::StandardUniform;
+    use rand::prelude::*;
+    use std::fmt::Debug;
+
+    fn check_hybrid_jit<K>(seed: u64, boundaries: &[K])
+    where
+        K: PackedKey + Debug,
+        StandardUniform: Distribution<K>,
+    {
+        let mut rng = StdRng::seed_from_u64(seed);
+        for len in [0, 1, 2, 3, 10, 100, 1000] {
+            let (tree, probes) = random_tree(len, boundaries, || rng.random::<K>());
+            // From searching the whole tree to compiling every node
+            for levels in [0, 1, 3, default_levels::<K>(), 64] {
+                let compiled = compile_with_levels(&tree, levels);
+                check_against_tree(&tree, &probes, |probe| compiled.lookup(probe), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hybrid_jit_correctness() {
+        check_hybrid_jit::<i32>(1, &[i32::MIN, -1, 0, 1, i32::MAX]);
+        check_hybrid_jit::<u32>(2, &[0, 1, i32::MAX as u32 + 1, u32::MAX - 1, u32::MAX]);
+        check_hybrid_jit::<i64>(3, &[i64::MIN, -1, 0, 1, i64::MAX]);
+        check_hybrid_jit::<u64>(4, &[0, 1, i64::MAX as u64 + 1, u64::MAX - 1, u64::MAX]);
+        check_hybrid_jit::<i16>(5, &[i16::MIN, -1, 0, 1, i16::MAX]);
+    }
+
+    #[test]
+    fn test_default_levels_fit_the_icache() {
+        assert_eq!(levels_for_icache::<i32>(32 * 1024), 9);
+        assert_eq!(levels_for_icache::<u64>(32 * 1024), 8);
+        assert_eq!(levels_for_icache::<i32>(0), 0);
+        assert_eq!(
+            default_levels::<i32>(),
+            levels_for_icache::<i32>(L1I_CACHE_BYTES)
+        );
+
+        let tree: AvlTree<i32, i32> = (0..100_000).map(|k| (k, -k)).collect();
+        let compiled = compile(&tree);
+        assert_eq!(compiled.lookup(&0), Some(0));
+        assert_eq!(compiled.lookup(&99_999), Some(-99_999));
+        assert_eq!(compiled.lookup(&100_000), None);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::avl::{AvlTree, Node};
+use crate::codegen::{self, Emitter, KeyCodegen};
+use crate::compiled::{CompiledLookup, JitValue};
+
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi, dynasm};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Lookup counts recorded by an `InstrumentedLookup`, by position in the key order of the tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Lookups that found each key.
+    pub hits: Vec<u64>,
+    /// Lookups that found no key, by the gap they fell into: `misses[i]` counts probes between
+    /// the keys at positions `i - 1` and `i`, so there is one more gap than there are keys.
+    pub misses: Vec<u64>,
+}
+
+/// A compiled lookup that counts how often each key is found and each gap between keys is
+/// probed, for `compile_profiled` to lay out the next compilation by.
+pub struct InstrumentedLookup<K, V> {
+    compiled: CompiledLookup<K, V>,
+    // Hit counters for the keys, followed by the miss counters for the gaps. The generated code
+    // holds their addresses, so they must not move while it lives.
+    counters: Box<[AtomicU64]>,
+    len: usize,
+}
+
+impl<K: KeyCodegen, V: JitValue> InstrumentedLookup<K, V> {
+    /// Looks up `key` with the compiled code, counting the outcome.
+    pub fn lookup(&self, key: &K) -> Option<V> {
+        self.compiled.lookup(key)
+    }
+
+    /// Returns the compiled lookup, which keeps counting for as long as it lives.
+    pub fn compiled(&self) -> &CompiledLookup<K, V> {
+        &self.compiled
+    }
+
+    /// Returns the counts recorded so far.
+    pub fn profile(&self) -> Profile {
+        let counts: Vec<u64> = self
+            .counters
+            .iter()
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .collect();
+        Profile {
+            hits: counts[..self.len].to_vec(),
+            misses: counts[self.len..].to_vec(),
+        }
+    }
+
+    /// Clears the counts recorded so far.
+    pub fn reset(&self) {
+        for counter in self.counters.iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Compiles `AvlTree::lookup` for the given tree, with code that counts every outcome in a
+/// buffer of counters: one for each key, and one for each gap between keys that misses fall
+/// into. The counters are incremented atomically, so the lookup can be shared between threads.
+pub fn compile_instrumented<K: KeyCodegen, V: JitValue>(
+    tree: &AvlTree<K, V>,
+) -> InstrumentedLookup<K, V> {
+    let len = tree.len();
+    let counters: Box<[AtomicU64]> = (0..2 * len + 1).map(|_| AtomicU64::new(0)).collect();
+
+    let mut ops = Emitter::new();
+
+    let start = ops.offset();
+    K::emit_load_probe(&mut ops);
+
+    let (hits, misses) = counters.split_at(len);
+    match &tree.root {
+        Some(node) => build_instrumented_asm(&mut ops, node, hits, misses, 0),
+        None => emit_count_miss(&mut ops, &misses[0]),
+    }
+
+    let buf = ops.finalize();
+    InstrumentedLookup {
+        compiled: CompiledLookup::new(buf, start, tree.version()),
+        counters,
+        len,
+    }
+}
+
+// Recursive helper to generate the instrumented code for a subtree whose keys start at
+// position `base`. Like for rank queries, the position of every key and gap reached is known
+// at compile time, so each outcome increments its own counter.
+fn build_instrumented_asm<K: KeyCodegen, V: JitValue>(
+    ops: &mut Emitter,
+    node: &Node<K, V>,
+    hits: &[AtomicU64],
+    misses: &[AtomicU64],
+    base: usize,
+) {
+    let left_label = ops.new_dynamic_label();
+    let right_label = ops.new_dynamic_label();
+    let position = base + Node::size(&node.left);
+
+    node.key.emit_compare(ops, left_label, right_label);
+    emit_count(ops, &hits[position]);
+    codegen::emit_found(ops, node.value.to_bits());
+
+    dynasm!(ops; =>left_label);
+    match &node.left {
+        Some(left) => build_instrumented_asm(ops, left, hits, misses, base),
+        None => emit_count_miss(ops, &misses[position]),
+    }
+
+    dynasm!(ops; =>right_label);
+    match &node.right {
+        Some(right) => build_instrumented_asm(ops, right, hits, misses, position + 1),
+        None => emit_count_miss(ops, &misses[position + 1]),
+    }
+}
+
+fn emit_count(ops: &mut Emitter, counter: &AtomicU64) {
+    dynasm!(ops
+        ; mov rax, QWORD counter.as_ptr() as i64
+        ; lock inc QWORD [rax]
+    );
+}
+
+fn emit_count_miss(ops: &mut Emitter, counter: &AtomicU64) {
+    emit_count(ops, counter);
+    dynasm!(ops; xor eax, eax);
+    ops.emit_ret();
+}
+
+/// Compiles `AvlTree::lookup` for the given tree as a search tree weighted by `profile`,
+/// which should have been recorded by an `InstrumentedLookup` of the same keys.
+///
+/// Each subtree is rooted at the key that best halves the lookups reaching it (Mehlhorn's
+/// approximation of the optimal search tree), so frequently found keys sit near the root and
+/// frequently probed gaps are left early. Every count is incremented by one, which keeps keys
+/// that were never looked up in balanced subtrees. The node blocks are emitted from the most
+/// to the least reached, keeping the hot code contiguous.
+///
+/// # Panics
+///
+/// Panics if the profile does not have the counts for as many keys as the tree.
+pub fn compile_profiled<K: KeyCodegen, V: JitValue>(
+    tree: &AvlTree<K, V>,
+    profile: &Profile,
+) -> CompiledLookup<K, V> {
+    let entries: Vec<(&K, &V)> = tree.iter().collect();
+    assert!(
+        profile.hits.len() == entries.len() && profile.misses.len() == entries.len() + 1,
+        "profile of {} keys does not match a tree of {}",
+        profile.hits.len(),
+        entries.len()
+    );
+    let nodes = weighted_tree(profile);
+
+    let mut ops = Emitter::new();
+
+    let start = ops.offset();
+    K::emit_load_probe(&mut ops);
+
+    let not_found_label = ops.new_dynamic_label();
+    let labels: Vec<DynamicLabel> = nodes.iter().map(|_| ops.new_dynamic_label()).collect();
+    let child_label = |child: Option<usize>| child.map_or(not_found_label, |child| labels[child]);
+
+    // The root is reached by every lookup, so it comes first and the entry falls into it
+    let mut order: Vec<usize> = (0..nodes.len()).collect();
+    order.sort_by_key(|&node| std::cmp::Reverse(nodes[node].reach));
+    for node in order {
+        let WeightedNode {
+            position,
+            left,
+            right,
+            ..
+        } = nodes[node];
+        let (key, value) = entries[position];
+        dynasm!(ops; =>labels[node]);
+        key.emit_compare(&mut ops, child_label(left), child_label(right));
+        codegen::emit_found(&mut ops, value.to_bits());
+    }
+
+    dynasm!(ops
+        ; =>not_found_label
+        ; xor eax, eax
+    );
+    ops.emit_ret();
+
+    let buf = ops.finalize();
+    CompiledLookup::new(buf, start, tree.version())
+}
+
+// A node of the weighted search tree: the position of its key, its children, and the number of
+// lookups reaching it
+#[derive(Clone, Copy, Debug)]
+struct WeightedNode {
+    position: usize,
+    left: Option<usize>,
+    right: Option<usize>,
+    reach: u64,
+}
+
+// Builds the weighted search tree for the profile, with the root first
+fn weighted_tree(profile: &Profile) -> Vec<WeightedNode> {
+    // Prefix sums of the weights interleaved in key order, gap 0, key 0, gap 1, ..., gap n,
+    // so the weight of gap i is at 2i and that of key i at 2i + 1
+    let mut prefix = vec![0u64];
+    for (i, &miss) in profile.misses.iter().enumerate() {
+        prefix.push(prefix.last().unwrap() + miss + 1);
+        if let Some(&hit) = profile.hits.get(i) {
+            prefix.push(prefix.last().unwrap() + hit + 1);
+        }
+    }
+
+    let mut nodes = Vec::with_capacity(profile.hits.len());
+    build_weighted(&prefix, 0, profile.hits.len(), &mut nodes);
+    nodes
+}
+
+// Appends the subtree for the keys at positions `lo..hi` to `nodes`, in pre-order, and returns
+// the index of its root. The depth of a key is at most about log2(total / weight) + 2, so the
+// recursion stays shallow.
+fn build_weighted(
+    prefix: &[u64],
+    lo: usize,
+    hi: usize,
+    nodes: &mut Vec<WeightedNode>,
+) -> Option<usize> {
+    if lo == hi {
+        return None;
+    }
+
+    // The weights on either side of the key at `k`, including the gaps at the ends
+    let left = |k: usize| prefix[2 * k + 1] - prefix[2 * lo];
+    let right = |k: usize| prefix[2 * hi + 1] - prefix[2 * k + 2];
+    // `left` grows and `right` shrinks with `k`: find the first key where they cross
+    let (mut split, mut end) = (lo, hi);
+    while split < end {
+        let k = (split + end) / 2;
+        if left(k) < right(k) {
+            split = k + 1;
+        } else {
+            end = k;
+        }
+    }
+    let root = [split.saturating_sub(1).max(lo), split.min(hi - 1)]
+        .into_iter()
+        .min_by_key(|&k| left(k).max(right(k)))
+        .unwrap();
+
+    let index = nodes.len();
+    nodes.push(WeightedNode {
+        position: root,
+        left: None,
+        right: None,
+        reach: prefix[2 * hi + 1] - prefix[2 * lo],
+    });
+    nodes[index].left = build_weighted(prefix, lo, root, nodes);
+    nodes[index].right = build_weighted(prefix, root + 1, hi, nodes);
+    Some(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::check_against_tree;
+    use rand::prelude::*;
+
+    // Depth of every key in the weighted tree, by position
+    fn depths(nodes: &[WeightedNode]) -> Vec<usize> {
+        fn walk(nodes: &[WeightedNode], node: Option<usize>, depth: usize, out: &mut [usize]) {
+            if let Some(node) = node {
+                out[nodes[node].position] = depth;
+                walk(nodes, nodes[node].left, depth + 1, out);
+                walk(nodes, nodes[node].right, depth + 1, out);
+            }
+        }
+        let mut out = vec![0; nodes.len()];
+        walk(nodes, (!nodes.is_empty()).then_some(0), 1, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_instrumented_lookup_counts_outcomes() {
+        let tree: AvlTree<i32, i32> = (0..100).map(|k| (k * 2, -k)).collect();
+        let instrumented = compile_instrumented(&tree);
+        for probe in -5..205 {
+            assert_eq!(instrumented.lookup(&probe), tree.lookup(&probe));
+        }
+        for _ in 0..10 {
+            instrumented.lookup(&42);
+        }
+
+        let profile = instrumented.profile();
+        let mut hits = vec![1; 100];
+        hits[21] += 10;
+        let mut misses = vec![1; 101];
+        // Probes -5..0 fall before the first key, 199..205 after the last
+        misses[0] = 5;
+        misses[100] = 6;
+        assert_eq!(profile, Profile { hits, misses });
+
+        instrumented.reset();
+        assert_eq!(
+            instrumented.profile(),
+            Profile {
+                hits: vec![0; 100],
+                misses: vec![0; 101],
+            }
+        );
+        let empty = compile_instrumented(&AvlTree::<i32, i32>::new());
+        assert_eq!(empty.lookup(&1), None);
+        assert_eq!(empty.profile().misses, [1]);
+    }
+
+    #[test]
+    fn test_weighted_tree_roots_hot_keys() {
+        let mut profile = Profile {
+            hits: vec![0; 1000],
+            misses: vec![0; 1001],
+        };
+        profile.hits[900] = 1_000_000;
+        profile.hits[100] = 1000;
+        let nodes = weighted_tree(&profile);
+        assert_eq!(nodes.len(), 1000);
+        assert_eq!(nodes[0].position, 900);
+        let depth = depths(&nodes);
+        assert!(depth[100] <= 4, "depth {}", depth[100]);
+
+        // Without counts the tree is balanced
+        profile.hits.fill(0);
+        let depth = depths(&weighted_tree(&profile));
+        assert_eq!(depth.iter().max(), Some(&10));
+
+        // A hot gap is left early
+        profile.misses[500] = 1_000_000;
+        let nodes = weighted_tree(&profile);
+        assert!(matches!(nodes[0].position, 499 | 500));
+        assert!(
+            weighted_tree(&Profile {
+                hits: Vec::new(),
+                misses: vec![0],
+            })
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_profiled_lookup_correctness() {
+        let mut rng = StdRng::seed_from_u64(2424);
+        let keys: Vec<i32> = (0..2000)
+            .map(|_| rng.random_range(-10_000..10_000))
+            .collect();
+        let tree: AvlTree<i32, i32> = keys.iter().map(|&k| (k, k.wrapping_mul(7))).collect();
+        let instrumented = compile_instrumented(&tree);
+        // Skewed traffic: a few hot keys and a hot range of misses
+        for _ in 0..20_000 {
+            let probe = match rng.random_range(0..4) {
+                0 => keys[rng.random_range(0..10)],
+                1 => rng.random_range(20_000..20_100),
+                _ => rng.random_range(-10_000..10_000),
+            };
+            assert_eq!(instrumented.lookup(&probe), tree.lookup(&probe));
+        }
+
+        let profile = instrumented.profile();
+        assert_eq!(
+            profile.hits.iter().chain(&profile.misses).sum::<u64>(),
+            20_000
+        );
+        let compiled = compile_profiled(&tree, &profile);
+        assert!(!compiled.is_stale(&tree));
+        let probes: Vec<i32> = (-11_000..21_000).collect();
+        check_against_tree(&tree, &probes, |probe| compiled.lookup(probe), None);
+    }
+
+    #[test]
+    fn test_profiled_byte_array_keys() {
+        let mut rng = StdRng::seed_from_u64(2425);
+        let keys: Vec<[u8; 16]> = (0..500).map(|_| rng.random()).collect();
+        let tree: AvlTree<[u8; 16], usize> =
+            keys.iter().enumerate().map(|(i, &k)| (k, i)).collect();
+        let instrumented = compile_instrumented(&tree);
+        for _ in 0..5000 {
+            instrumented.lookup(&keys[rng.random_range(0..5)]);
+        }
+
+        let compiled = compile_profiled(&tree, &instrumented.profile());
+        let probes: Vec<[u8; 16]> = (0..500).map(|_| rng.random()).collect();
+        check_against_tree(&tree, &probes, |probe| compiled.lookup(probe), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not match")]
+    fn test_profiled_rejects_mismatched_profile() {
+        let tree: AvlTree<i32, i32> = (0..10).map(|k| (k, k)).collect();
+        compile_profiled(&tree, &Profile::default());
+    }
+}
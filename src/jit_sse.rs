@@ -1,245 +1,90 @@
-use crate::avl::Node;
+use crate::avl::AvlTree;
+use crate::backend::Backend;
+use crate::codegen::{self, Emitter, KeyOps};
+use crate::compiled::{CompiledLookup, CompiledNeighbor, CompiledRank, JitValue};
+use crate::jit::Neighbor;
 
-use dynasmrt::{DynasmApi, DynasmLabelApi, ExecutableBuffer, dynasm};
-use std::collections::HashMap;
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi, dynasm};
 
-// The function signature we are compiling to: takes a key pointer, returns a value or -1
-pub type JittedLookup = unsafe extern "sysv64" fn(key_ptr: *const u8) -> i32;
-
-pub fn compile_scalar(root: &Option<Box<Node<[u8; 16], i32>>>) -> (ExecutableBuffer, JittedLookup) {
-    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
-
-    let start = ops.offset();
-
-    // A map from node key to the dynasm label for that node's code block
-    let mut labels = HashMap::new();
-
-    // The label for the "not found" case
-    let not_found_label = ops.new_dynamic_label();
-
-    // Recursively build the assembly from the tree structure
-    if let Some(node) = root {
-        build_asm_scalar(&mut ops, node, &mut labels, not_found_label);
-    }
-
-    // "Not found" block: move -1 into the return register (rax) and return
-    dynasm!(ops
-        ; =>not_found_label
-        ; mov rax, -1
-        ; ret
-    );
-
-    // Finalize the buffer and cast it to a function pointer
-    let buf = ops.finalize().unwrap();
-    let func_ptr: JittedLookup = unsafe { std::mem::transmute(buf.ptr(start)) };
-
-    (buf, func_ptr)
+/// Compiles `AvlTree::lookup` for the given tree using GPR comparisons.
+pub fn compile_scalar<V: JitValue>(tree: &AvlTree<[u8; 16], V>) -> CompiledLookup<[u8; 16], V> {
+    codegen::compile(tree)
 }
 
-// Recursive helper to generate assembly for a subtree using GPRs
-fn build_asm_scalar(
-    ops: &mut dynasmrt::x64::Assembler,
-    node: &Node<[u8; 16], i32>,
-    labels: &mut HashMap<[u8; 16], dynasmrt::DynamicLabel>,
-    not_found_label: dynasmrt::DynamicLabel,
-) {
-    // Get or create a label for the current node's code block
-    let self_label = *labels
-        .entry(node.key)
-        .or_insert_with(|| ops.new_dynamic_label());
-
-    let found_label = ops.new_dynamic_label();
-    let go_left_path = ops.new_dynamic_label();
-    let go_right_path = ops.new_dynamic_label();
-
-    // Define the entry point for this node's logic
-    dynasm!(ops; =>self_label);
-
-    // Input key pointer is in rdi (sysv64 calling convention)
-
-    // Load input key (first 8 bytes) into r8
-    dynasm!(ops; mov r8, QWORD [rdi]);
-    // Load input key (next 8 bytes) into r9
-    dynasm!(ops; mov r9, QWORD [rdi + 8]);
-
-    let node_key_part1 = u64::from_le_bytes(node.key[0..8].try_into().unwrap());
-    let node_key_part2 = u64::from_le_bytes(node.key[8..16].try_into().unwrap());
-
-    // Load node's key (first 8 bytes) into r10
-    dynasm!(ops; mov r10, QWORD node_key_part1 as i64);
-    // Load node's key (next 8 bytes) into r11
-    dynasm!(ops; mov r11, QWORD node_key_part2 as i64);
-
-    // Compare first 8 bytes (r8 vs r10)
-    dynasm!(ops; cmp r8, r10);
-    dynasm!(ops; jl =>go_left_path);
-    dynasm!(ops; jg =>go_right_path);
-
-    // If first 8 bytes are equal, compare next 8 bytes (r9 vs r11)
-    dynasm!(ops; cmp r9, r11);
-    dynasm!(ops; jl =>go_left_path);
-    dynasm!(ops; jg =>go_right_path);
-
-    // If both 8-byte chunks are equal, keys are equal
-    dynasm!(ops; jmp =>found_label);
-
-    // --- Traversal Logic ---
-    dynasm!(ops; =>go_left_path);
-    if let Some(left) = &node.left {
-        let left_label = *labels
-            .entry(left.key)
-            .or_insert_with(|| ops.new_dynamic_label());
-        dynasm!(ops; jmp =>left_label);
-    } else {
-        dynasm!(ops; jmp =>not_found_label);
-    }
-
-    dynasm!(ops; =>go_right_path);
-    if let Some(right) = &node.right {
-        let right_label = *labels
-            .entry(right.key)
-            .or_insert_with(|| ops.new_dynamic_label());
-        dynasm!(ops; jmp =>right_label);
-    } else {
-        dynasm!(ops; jmp =>not_found_label);
-    }
-
-    // If we jumped here, it means the key was equal.
-    // Move the node's value into the return register (rax) and return.
-    dynasm!(ops
-        ; =>found_label
-        ; mov rax, node.value as i32
-        ; ret
-    );
+/// Compiles `AvlTree::lookup` for the given tree using SSE comparisons of whole keys.
+pub fn compile_sse<V: JitValue>(tree: &AvlTree<[u8; 16], V>) -> CompiledLookup<[u8; 16], V> {
+    codegen::compile_with(tree, &sse_key_ops())
+}
 
-    // Recursively build assembly for children. Pre-order traversal is natural here.
-    if let Some(left) = &node.left {
-        build_asm_scalar(ops, left, labels, not_found_label);
-    }
-    if let Some(right) = &node.right {
-        build_asm_scalar(ops, right, labels, not_found_label);
+fn sse_key_ops() -> KeyOps<[u8; 16]> {
+    Backend::Sse.assert_supported();
+    KeyOps {
+        load_probe: load_probe_sse,
+        compare: compare_sse,
     }
 }
 
-pub fn compile_sse(root: &Option<Box<Node<[u8; 16], i32>>>) -> (ExecutableBuffer, JittedLookup) {
-    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
-
-    let start = ops.offset();
-
-    let mut labels = HashMap::new();
-    let not_found_label = ops.new_dynamic_label();
+fn load_probe_sse(ops: &mut Emitter) {
+    // Load the probe key into xmm0 once, every node compares against it
+    dynasm!(ops; movdqu xmm0, [rdi]);
+}
 
-    if let Some(node) = root {
-        build_asm_sse(&mut ops, node, &mut labels, not_found_label);
-    }
+// A single byte-wise equality compare against the node key in the constant pool decides
+// equality, and the first differing byte, the lowest bit of the inverted mask, decides the order.
+fn compare_sse(key: &[u8; 16], ops: &mut Emitter, less: DynamicLabel, greater: DynamicLabel) {
+    let data = ops.constant(key, 16);
+    let equal = ops.new_dynamic_label();
 
     dynasm!(ops
-        ; =>not_found_label
-        ; mov rax, -1
-        ; ret
+        ; movdqa xmm1, xmm0
+        ; pcmpeqb xmm1, [=>data]
+        ; pmovmskb eax, xmm1
+        ; xor eax, 0xFFFF
+        ; jz =>equal
+        ; bsf eax, eax
+        ; lea rdx, [=>data]
     );
+    codegen::branch_first_difference(ops, less, greater);
+    dynasm!(ops; =>equal);
+}
 
-    let buf = ops.finalize().unwrap();
-    let func_ptr: JittedLookup = unsafe { std::mem::transmute(buf.ptr(start)) };
-
-    (buf, func_ptr)
+/// Compiles `AvlTree::rank` for the given tree using GPR comparisons.
+pub fn compile_rank<V>(tree: &AvlTree<[u8; 16], V>) -> CompiledRank<[u8; 16]> {
+    codegen::compile_rank(tree)
 }
 
-fn build_asm_sse(
-    ops: &mut dynasmrt::x64::Assembler,
-    node: &Node<[u8; 16], i32>,
-    labels: &mut HashMap<[u8; 16], dynasmrt::DynamicLabel>,
-    not_found_label: dynasmrt::DynamicLabel,
-) {
-    let self_label = *labels
-        .entry(node.key)
-        .or_insert_with(|| ops.new_dynamic_label());
-
-    let found_label = ops.new_dynamic_label();
-    let go_left_path = ops.new_dynamic_label();
-    let go_right_path = ops.new_dynamic_label();
-
-    dynasm!(ops; =>self_label);
-
-    // Load input key into xmm0 (128-bit SSE register)
-    dynasm!(ops; movups xmm0, [rdi]);
-
-    // Load node's key into xmm1
-    let node_key_part1 = u64::from_le_bytes(node.key[0..8].try_into().unwrap());
-    let node_key_part2 = u64::from_le_bytes(node.key[8..16].try_into().unwrap());
-
-    dynasm!(ops; mov r10, QWORD node_key_part1 as i64);
-    dynasm!(ops; mov r11, QWORD node_key_part2 as i64);
-    dynasm!(ops; movq xmm1, r10);
-    dynasm!(ops; pinsrq xmm1, r11, 1);
-
-    // Compare for equality (byte-wise)
-    dynasm!(ops; pcmpeqb xmm0, xmm1);
-    // Get mask of equal bytes
-    dynasm!(ops; pmovmskb eax, xmm0);
-    // If mask is not all ones (0xFFFF), keys are not equal
-    dynasm!(ops; cmp eax, 0xFFFF);
-    dynasm!(ops; jne >check_ordering);
-
-    // If keys are equal, jump to found_label
-    dynasm!(ops; jmp =>found_label);
-
-    // If not equal, fall back to GPR comparison for ordering
-    dynasm!(ops; check_ordering:);
-    // Reload input key parts into GPRs for comparison
-    dynasm!(ops; mov r8, QWORD [rdi]);
-    dynasm!(ops; mov r9, QWORD [rdi + 8]);
-
-    // Compare first 8 bytes (r8 vs r10)
-    dynasm!(ops; cmp r8, r10);
-    dynasm!(ops; jl =>go_left_path);
-    dynasm!(ops; jg =>go_right_path);
-
-    // If first 8 bytes are equal, compare next 8 bytes (r9 vs r11)
-    dynasm!(ops; cmp r9, r11);
-    dynasm!(ops; jl =>go_left_path);
-    dynasm!(ops; jg =>go_right_path);
-
-    // --- Traversal Logic ---
-    dynasm!(ops; =>go_left_path);
-    if let Some(left) = &node.left {
-        let left_label = *labels
-            .entry(left.key)
-            .or_insert_with(|| ops.new_dynamic_label());
-        dynasm!(ops; jmp =>left_label);
-    } else {
-        dynasm!(ops; jmp =>not_found_label);
-    }
+/// Compiles the given neighbor query (`AvlTree::floor` and friends) using GPR comparisons.
+pub fn compile_neighbor_scalar<V: JitValue>(
+    tree: &AvlTree<[u8; 16], V>,
+    kind: Neighbor,
+) -> CompiledNeighbor<[u8; 16], V> {
+    codegen::compile_neighbor_with(tree, kind, &KeyOps::scalar(), emit_neighbor_key)
+}
 
-    dynasm!(ops; =>go_right_path);
-    if let Some(right) = &node.right {
-        let right_label = *labels
-            .entry(right.key)
-            .or_insert_with(|| ops.new_dynamic_label());
-        dynasm!(ops; jmp =>right_label);
-    } else {
-        dynasm!(ops; jmp =>not_found_label);
-    }
+/// Compiles the given neighbor query using SSE comparisons of whole keys.
+pub fn compile_neighbor_sse<V: JitValue>(
+    tree: &AvlTree<[u8; 16], V>,
+    kind: Neighbor,
+) -> CompiledNeighbor<[u8; 16], V> {
+    codegen::compile_neighbor_with(tree, kind, &sse_key_ops(), emit_neighbor_key)
+}
 
+// Writes the key to the out pointer (in rsi)
+fn emit_neighbor_key(ops: &mut Emitter, key: &[u8; 16]) {
+    let key_part1 = u64::from_le_bytes(key[0..8].try_into().unwrap());
+    let key_part2 = u64::from_le_bytes(key[8..16].try_into().unwrap());
     dynasm!(ops
-        ; =>found_label
-        ; mov rax, node.value as i32
-        ; ret
+        ; mov rax, QWORD key_part1 as i64
+        ; mov [rsi], rax
+        ; mov rax, QWORD key_part2 as i64
+        ; mov [rsi + 8], rax
     );
-
-    if let Some(left) = &node.left {
-        build_asm_sse(ops, left, labels, not_found_label);
-    }
-    if let Some(right) = &node.right {
-        build_asm_sse(ops, right, labels, not_found_label);
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::avl::AvlTree;
-    use crate::jit;
+    use crate::test_util::{NEIGHBORS, check_against_tree, expected_neighbor};
     use rand::prelude::*;
 
     // Helper function to generate random 16-byte arrays
@@ -250,22 +95,25 @@ mod tests {
     }
 
     #[test]
-    fn test_i32_jit_correctness() {
+    fn test_str_jit_scalar_correctness() {
         let tree_size = 1000;
         let lookups = 10000;
-        let seed = 12345;
+        let seed = 54321;
 
         let mut tree = AvlTree::new();
         let mut rng = StdRng::seed_from_u64(seed);
-        let mut keys: Vec<i32> = (0..tree_size).collect();
+        let mut keys: Vec<[u8; 16]> = Vec::with_capacity(tree_size as usize);
+        for _ in 0..tree_size {
+            keys.push(generate_random_bytes(&mut rng));
+        }
         keys.shuffle(&mut rng);
         for &key in &keys {
-            tree.insert(key, key);
+            tree.insert(key, 1);
         }
 
         let mut lookup_keys = Vec::with_capacity(lookups as usize);
         for _ in 0..lookups {
-            lookup_keys.push(rng.random_range(0..tree_size));
+            lookup_keys.push(generate_random_bytes(&mut rng));
         }
 
         let mut generic_correct_count = 0;
@@ -275,26 +123,25 @@ mod tests {
             }
         }
 
-        let (_buf, jitted_fn) = jit::compile(&tree.root);
+        let compiled = compile_scalar(&tree);
         let mut jit_correct_count = 0;
         for &key in &lookup_keys {
-            let result = unsafe { jitted_fn(key) };
-            if result != -1 {
+            if compiled.lookup(&key).is_some() {
                 jit_correct_count += 1;
             }
         }
 
         assert_eq!(
             generic_correct_count, jit_correct_count,
-            "Mismatch in i32 JIT correctness"
+            "Mismatch in string GPR JIT correctness"
         );
     }
 
     #[test]
-    fn test_str_jit_scalar_correctness() {
+    fn test_str_jit_sse_correctness() {
         let tree_size = 1000;
         let lookups = 10000;
-        let seed = 54321;
+        let seed = 67890;
 
         let mut tree = AvlTree::new();
         let mut rng = StdRng::seed_from_u64(seed);
@@ -319,62 +166,106 @@ mod tests {
             }
         }
 
-        let (_buf, jitted_fn) = compile_scalar(&tree.root);
+        let compiled = compile_sse(&tree);
         let mut jit_correct_count = 0;
         for &key in &lookup_keys {
-            let result = unsafe { jitted_fn(key.as_ptr()) };
-            if result != -1 {
+            if compiled.lookup(&key).is_some() {
                 jit_correct_count += 1;
             }
         }
 
         assert_eq!(
             generic_correct_count, jit_correct_count,
-            "Mismatch in string GPR JIT correctness"
+            "Mismatch in string SSE JIT correctness"
         );
     }
 
     #[test]
-    fn test_str_jit_sse_correctness() {
-        let tree_size = 1000;
-        let lookups = 10000;
-        let seed = 67890;
-
+    fn test_str_jit_rank() {
         let mut tree = AvlTree::new();
-        let mut rng = StdRng::seed_from_u64(seed);
-        let mut keys: Vec<[u8; 16]> = Vec::with_capacity(tree_size as usize);
-        for _ in 0..tree_size {
+        let mut rng = StdRng::seed_from_u64(9090);
+        let mut keys: Vec<[u8; 16]> = Vec::with_capacity(1000);
+        for _ in 0..1000 {
             keys.push(generate_random_bytes(&mut rng));
         }
-        keys.shuffle(&mut rng);
         for &key in &keys {
             tree.insert(key, 1);
         }
 
-        let mut lookup_keys = Vec::with_capacity(lookups as usize);
-        for _ in 0..lookups {
-            lookup_keys.push(generate_random_bytes(&mut rng));
+        let compiled = compile_scalar(&tree);
+        let compiled_rank = compile_rank(&tree);
+        let probes: Vec<[u8; 16]> = (0..1000).map(|_| generate_random_bytes(&mut rng)).collect();
+        check_against_tree(
+            &tree,
+            &probes,
+            |probe| compiled.lookup(probe),
+            Some(&compiled_rank),
+        );
+    }
+
+    #[test]
+    fn test_str_jit_neighbors() {
+        let mut tree = AvlTree::new();
+        let mut rng = StdRng::seed_from_u64(2808);
+        let mut keys = Vec::new();
+        for _ in 0..500 {
+            let key = generate_random_bytes(&mut rng);
+            keys.push(key);
+            // Values wider than the 32-bit immediates of stores
+            tree.insert(key, rng.random::<u64>());
+        }
+        // Probes sharing the first qword with a present key exercise the second comparison
+        let mut probes = keys.clone();
+        for key in &keys {
+            let mut probe = *key;
+            probe[15] ^= 1;
+            probes.push(probe);
+            probes.push(generate_random_bytes(&mut rng));
         }
 
-        let mut generic_correct_count = 0;
-        for &key in &lookup_keys {
-            if tree.lookup(&key).is_some() {
-                generic_correct_count += 1;
+        let compilers: [fn(&_, _) -> _; 2] = [compile_neighbor_scalar, compile_neighbor_sse];
+        for compile in compilers {
+            for kind in NEIGHBORS {
+                let compiled = compile(&tree, kind);
+                for probe in &probes {
+                    assert_eq!(
+                        compiled.neighbor(probe),
+                        expected_neighbor(&tree, probe, kind)
+                    );
+                }
             }
         }
+    }
 
-        let (_buf, jitted_fn) = compile_sse(&tree.root);
-        let mut jit_correct_count = 0;
-        for &key in &lookup_keys {
-            let result = unsafe { jitted_fn(key.as_ptr()) };
-            if result != -1 {
-                jit_correct_count += 1;
-            }
+    #[test]
+    fn test_jit_returns_sentinel_like_values() {
+        // Values such as -1 used to be indistinguishable from a miss
+        let mut tree = AvlTree::new();
+        tree.insert([7; 16], -1);
+        for compiled in [compile_scalar(&tree), compile_sse(&tree)] {
+            assert_eq!(compiled.lookup(&[7; 16]), Some(-1));
+            assert_eq!(compiled.lookup(&[0xAA; 16]), None);
         }
+    }
 
-        assert_eq!(
-            generic_correct_count, jit_correct_count,
-            "Mismatch in string SSE JIT correctness"
-        );
+    #[test]
+    fn test_str_jit_finds_every_key() {
+        let mut tree = AvlTree::new();
+        let mut rng = StdRng::seed_from_u64(1111);
+        let mut keys: Vec<[u8; 16]> = (0..2000).map(|_| generate_random_bytes(&mut rng)).collect();
+        // Keys differing only in their last bytes, or in the sign bit of a qword, trip up
+        // comparisons that do not follow the lexicographic order
+        for i in 0..=255u8 {
+            keys.push([0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, i]);
+            keys.push([i; 16]);
+        }
+        for (i, &key) in keys.iter().enumerate() {
+            tree.insert(key, i as i32);
+        }
+
+        let probes: Vec<[u8; 16]> = (0..2000).map(|_| generate_random_bytes(&mut rng)).collect();
+        for compiled in [compile_scalar(&tree), compile_sse(&tree)] {
+            check_against_tree(&tree, &probes, |probe| compiled.lookup(probe), None);
+        }
     }
 }
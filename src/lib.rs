@@ -0,0 +1,16 @@
+pub mod avl;
+pub mod backend;
+pub mod codegen;
+pub mod compiled;
+mod emitter;
+pub mod jit;
+pub mod jit_avx;
+pub mod jit_bytes;
+pub mod jit_eytzinger;
+pub mod jit_f64;
+pub mod jit_hybrid;
+pub mod jit_profile;
+pub mod jit_sse;
+
+#[cfg(test)]
+mod test_util;
@@ -1,14 +1,13 @@
-mod avl;
-mod jit;
-mod jit_sse;
-
-use avl::AvlTree;
+use lightning_avl::avl::AvlTree;
+use lightning_avl::{codegen, jit, jit_eytzinger, jit_hybrid, jit_sse};
 use rand::prelude::*;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 // Constants for i32 keys
 const I32_TREE_SIZE: i32 = 100_000;
 const I32_LOOKUPS: i32 = 10_000_000;
+// Keys per call of the batched lookup
+const BATCH_SIZE: usize = 4096;
 
 // Constants for [u8; 16] keys
 const STR_TREE_SIZE: i32 = 10_000;
@@ -22,6 +21,11 @@ fn generate_random_bytes(rng: &mut StdRng) -> [u8; 16] {
     bytes
 }
 
+// Average time of a single lookup, in nanoseconds
+fn per_lookup_ns(duration: Duration, lookups: i32) -> f64 {
+    duration.as_secs_f64() * 1e9 / lookups as f64
+}
+
 fn main() {
     println!("*** JIT Compiled AVL Tree Lookup in Rust ***");
 
@@ -48,15 +52,15 @@ fn main() {
     let generic_duration_i32 = start.elapsed();
     println!("  -> Generic lookup took: {:?}", generic_duration_i32);
 
-    if let Some(_root_node) = &tree_i32.root {
+    if !tree_i32.is_empty() {
         println!("\n[2] Benchmarking JIT lookup with dynasm-rs (i32 keys)...");
         let start = Instant::now();
-        let (_buf, jitted_fn_dynasm) = jit::compile(&tree_i32.root);
+        let compiled_dynasm = codegen::compile(&tree_i32);
         let dynasm_compile_duration_i32 = start.elapsed();
 
         let start = Instant::now();
         for &key in &lookup_keys_i32 {
-            unsafe { jitted_fn_dynasm(key) };
+            let _ = compiled_dynasm.lookup(&key);
         }
         let dynasm_run_duration_i32 = start.elapsed();
         println!(
@@ -68,21 +72,139 @@ fn main() {
             dynasm_run_duration_i32
         );
 
+        // The keys are the dense range 0..I32_TREE_SIZE, which `jit::compile` looks up in a
+        // single value table instead of comparing against the nodes
+        println!("\n[3] Benchmarking table JIT lookup (i32 keys)...");
+        let start = Instant::now();
+        let compiled_table = jit::compile(&tree_i32);
+        let table_compile_duration_i32 = start.elapsed();
+
+        let start = Instant::now();
+        for &key in &lookup_keys_i32 {
+            let _ = compiled_table.lookup(&key);
+        }
+        let table_run_duration_i32 = start.elapsed();
+        println!(
+            "  -> Table compilation took: {:?}",
+            table_compile_duration_i32
+        );
+        println!("  -> Table JIT lookup took:  {:?}", table_run_duration_i32);
+
+        println!("\n[4] Benchmarking branchless Eytzinger JIT lookup (i32 keys)...");
+        let start = Instant::now();
+        let compiled_eytzinger = jit_eytzinger::compile(&tree_i32);
+        let eytzinger_compile_duration_i32 = start.elapsed();
+
+        let start = Instant::now();
+        for &key in &lookup_keys_i32 {
+            let _ = compiled_eytzinger.lookup(&key);
+        }
+        let eytzinger_run_duration_i32 = start.elapsed();
+        println!(
+            "  -> Eytzinger compilation took: {:?}",
+            eytzinger_compile_duration_i32
+        );
+        println!(
+            "  -> Eytzinger JIT lookup took:  {:?}",
+            eytzinger_run_duration_i32
+        );
+
+        println!("\n[5] Benchmarking hybrid JIT lookup (i32 keys)...");
+        let start = Instant::now();
+        let compiled_hybrid = jit_hybrid::compile(&tree_i32);
+        let hybrid_compile_duration_i32 = start.elapsed();
+
+        let start = Instant::now();
+        for &key in &lookup_keys_i32 {
+            let _ = compiled_hybrid.lookup(&key);
+        }
+        let hybrid_run_duration_i32 = start.elapsed();
+        println!(
+            "  -> Hybrid compilation took: {:?}",
+            hybrid_compile_duration_i32
+        );
+        println!(
+            "  -> Hybrid JIT lookup took:  {:?}",
+            hybrid_run_duration_i32
+        );
+
+        println!("\n[6] Benchmarking batched Eytzinger JIT lookup (i32 keys)...");
+        let start = Instant::now();
+        let compiled_batch = jit_eytzinger::compile_batch(&tree_i32);
+        let batch_compile_duration_i32 = start.elapsed();
+
+        let mut batch_results = vec![None; BATCH_SIZE];
+        let start = Instant::now();
+        for keys in lookup_keys_i32.chunks(BATCH_SIZE) {
+            compiled_batch.lookup_batch(keys, &mut batch_results);
+        }
+        let batch_run_duration_i32 = start.elapsed();
+        println!(
+            "  -> Batched compilation took: {:?}",
+            batch_compile_duration_i32
+        );
+        println!(
+            "  -> Batched JIT lookup took:  {:?}",
+            batch_run_duration_i32
+        );
+
         println!("\n--- Summary ({} Lookups, i32 keys) ---", I32_LOOKUPS);
-        println!("Generic Rust: {:>18.2?}", generic_duration_i32);
+        println!("Generic Rust:  {:>18.2?}", generic_duration_i32);
         println!(
-            "Dynasm JIT:   {:>18.2?} (Compile: {:?})",
+            "Dynasm JIT:    {:>18.2?} (Compile: {:?})",
             dynasm_run_duration_i32, dynasm_compile_duration_i32
         );
         println!(
-            "\nSpeedup (Dynasm vs Generic):   {:.2}x",
+            "Table JIT:     {:>18.2?} (Compile: {:?})",
+            table_run_duration_i32, table_compile_duration_i32
+        );
+        println!(
+            "Eytzinger JIT: {:>18.2?} (Compile: {:?})",
+            eytzinger_run_duration_i32, eytzinger_compile_duration_i32
+        );
+        println!(
+            "Hybrid JIT:    {:>18.2?} (Compile: {:?})",
+            hybrid_run_duration_i32, hybrid_compile_duration_i32
+        );
+        println!(
+            "Batched JIT:   {:>18.2?} (Compile: {:?})",
+            batch_run_duration_i32, batch_compile_duration_i32
+        );
+        println!(
+            "Per lookup:    {:>15.2}ns (Generic), {:.2}ns (Dynasm), {:.2}ns (Table), {:.2}ns (Eytzinger), {:.2}ns (Hybrid), {:.2}ns (Batched)",
+            per_lookup_ns(generic_duration_i32, I32_LOOKUPS),
+            per_lookup_ns(dynasm_run_duration_i32, I32_LOOKUPS),
+            per_lookup_ns(table_run_duration_i32, I32_LOOKUPS),
+            per_lookup_ns(eytzinger_run_duration_i32, I32_LOOKUPS),
+            per_lookup_ns(hybrid_run_duration_i32, I32_LOOKUPS),
+            per_lookup_ns(batch_run_duration_i32, I32_LOOKUPS)
+        );
+        println!(
+            "\nSpeedup (Dynasm vs Generic):      {:.2}x",
             generic_duration_i32.as_secs_f64() / dynasm_run_duration_i32.as_secs_f64()
         );
+        println!(
+            "Speedup (Table vs Generic):       {:.2}x",
+            generic_duration_i32.as_secs_f64() / table_run_duration_i32.as_secs_f64()
+        );
+        println!(
+            "Speedup (Eytzinger vs Generic):   {:.2}x",
+            generic_duration_i32.as_secs_f64() / eytzinger_run_duration_i32.as_secs_f64()
+        );
+        println!(
+            "Speedup (Hybrid vs Generic):      {:.2}x",
+            generic_duration_i32.as_secs_f64() / hybrid_run_duration_i32.as_secs_f64()
+        );
+        println!(
+            "Speedup (Batched vs Eytzinger):   {:.2}x",
+            eytzinger_run_duration_i32.as_secs_f64() / batch_run_duration_i32.as_secs_f64()
+        );
     } else {
         println!("\nTree (i32) is empty, skipping JIT benchmarks.");
     }
 
-    // Benchmark for [u8; 16] keys
+    // Benchmark for [u8; 16] keys, the README compares its per-lookup times with and without
+    // loading the probe key once per lookup
     println!("\n--- Benchmarking with [u8; 16] keys ---");
     let mut tree_str = AvlTree::new();
     let mut rng_str = StdRng::seed_from_u64(SEED);
@@ -108,39 +230,45 @@ fn main() {
     let generic_duration_str = start.elapsed();
     println!("  -> Generic lookup took: {:?}", generic_duration_str);
 
-    if let Some(_root_node) = &tree_str.root {
+    if !tree_str.is_empty() {
         println!("\n[2] Benchmarking JIT lookup with GPR ([u8; 16] keys)...");
         let start = Instant::now();
-        let (_buf, jitted_fn_dynasm_gpr) = jit_sse::compile_scalar(&tree_str.root);
+        let compiled_dynasm_gpr = jit_sse::compile_scalar(&tree_str);
         let dynasm_compile_duration_gpr = start.elapsed();
 
         let start = Instant::now();
         for &key in &lookup_keys_str {
-            unsafe { jitted_fn_dynasm_gpr(key.as_ptr()) };
+            let _ = compiled_dynasm_gpr.lookup(&key);
         }
         let dynasm_run_duration_gpr = start.elapsed();
         println!(
             "  -> Dynasm compilation took: {:?}",
             dynasm_compile_duration_gpr
         );
-        println!("  -> Dynasm JIT lookup took:  {:?}", dynasm_run_duration_gpr);
+        println!(
+            "  -> Dynasm JIT lookup took:  {:?}",
+            dynasm_run_duration_gpr
+        );
         let dynasm_total_duration_gpr = dynasm_compile_duration_gpr + dynasm_run_duration_gpr;
 
-        println!("\n[3] Benchmarking JIT lookup with SSE 4.2 ([u8; 16] keys)...");
+        println!("\n[3] Benchmarking JIT lookup with SSE ([u8; 16] keys)...");
         let start = Instant::now();
-        let (_buf, jitted_fn_dynasm_sse) = jit_sse::compile_sse(&tree_str.root);
+        let compiled_dynasm_sse = jit_sse::compile_sse(&tree_str);
         let dynasm_compile_duration_sse = start.elapsed();
 
         let start = Instant::now();
         for &key in &lookup_keys_str {
-            unsafe { jitted_fn_dynasm_sse(key.as_ptr()) };
+            let _ = compiled_dynasm_sse.lookup(&key);
         }
         let dynasm_run_duration_sse = start.elapsed();
         println!(
             "  -> Dynasm compilation took: {:?}",
             dynasm_compile_duration_sse
         );
-        println!("  -> Dynasm JIT lookup took:  {:?}", dynasm_run_duration_sse);
+        println!(
+            "  -> Dynasm JIT lookup took:  {:?}",
+            dynasm_run_duration_sse
+        );
         let dynasm_total_duration_sse = dynasm_compile_duration_sse + dynasm_run_duration_sse;
 
         println!("\n--- Summary ({} Lookups, [u8; 16] keys) ---", STR_LOOKUPS);
@@ -153,6 +281,12 @@ fn main() {
             "Dynasm JIT (SSE): {:>18.2?} (Compile: {:?}, Run: {:?})",
             dynasm_total_duration_sse, dynasm_compile_duration_sse, dynasm_run_duration_sse
         );
+        println!(
+            "Per lookup:       {:>15.2}ns (Generic), {:.2}ns (GPR), {:.2}ns (SSE)",
+            per_lookup_ns(generic_duration_str, STR_LOOKUPS),
+            per_lookup_ns(dynasm_run_duration_gpr, STR_LOOKUPS),
+            per_lookup_ns(dynasm_run_duration_sse, STR_LOOKUPS)
+        );
         println!(
             "\nSpeedup (GPR vs Generic):   {:.2}x",
             generic_duration_str.as_secs_f64() / dynasm_total_duration_gpr.as_secs_f64()
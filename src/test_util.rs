@@ -0,0 +1,87 @@
+use crate::avl::AvlTree;
+use crate::codegen::{KeyCodegen, KeyValue, Neighbor};
+use crate::compiled::CompiledRank;
+
+use rand::prelude::*;
+use std::fmt::Debug;
+
+/// Builds a tree of `len` keys drawn from `random_key`, plus every other value of `boundaries`,
+/// and returns it with probes to check it with besides its keys: all of `boundaries` and 1000
+/// further random keys, most of them misses.
+pub fn random_tree<K: Ord + Copy>(
+    len: usize,
+    boundaries: &[K],
+    mut random_key: impl FnMut() -> K,
+) -> (AvlTree<K, u64>, Vec<K>) {
+    let mut tree = AvlTree::new();
+    for i in 0..len {
+        tree.insert(random_key(), i as u64);
+    }
+    for &key in boundaries.iter().step_by(2) {
+        tree.insert(key, u64::MAX);
+    }
+
+    let mut probes = boundaries.to_vec();
+    probes.extend((0..1000).map(|_| random_key()));
+    (tree, probes)
+}
+
+/// Checks a compiled lookup, and a compiled rank query if given, against `AvlTree::lookup` and
+/// `AvlTree::rank` for every key of the tree and every probe.
+pub fn check_against_tree<K, V>(
+    tree: &AvlTree<K, V>,
+    probes: &[K],
+    lookup: impl Fn(&K) -> Option<V>,
+    rank: Option<&CompiledRank<K>>,
+) where
+    K: KeyCodegen + Debug,
+    V: Copy + PartialEq + Debug,
+{
+    for probe in tree.keys().chain(probes) {
+        assert_eq!(
+            lookup(probe),
+            tree.lookup(probe),
+            "lookup of {probe:?} among {} keys",
+            tree.len()
+        );
+        if let Some(rank) = rank {
+            assert_eq!(rank.rank(probe), tree.rank(probe), "rank of {probe:?}");
+        }
+    }
+}
+
+// Bytes drawn from a small alphabet make long common prefixes likely, and include the bytes
+// where signed and unsigned comparisons disagree
+const ALPHABET: [u8; 5] = [0x00, 0x01, 0x7F, 0x80, 0xFF];
+
+/// Returns `len` random bytes of a small alphabet.
+pub fn alphabet_bytes(rng: &mut StdRng, len: usize) -> Vec<u8> {
+    (0..len).map(|_| *ALPHABET.choose(rng).unwrap()).collect()
+}
+
+/// Returns a random key of bytes of a small alphabet, see `alphabet_bytes`.
+pub fn alphabet_key<const N: usize>(rng: &mut StdRng) -> [u8; N] {
+    std::array::from_fn(|_| *ALPHABET.choose(rng).unwrap())
+}
+
+pub const NEIGHBORS: [Neighbor; 4] = [
+    Neighbor::Floor,
+    Neighbor::Ceiling,
+    Neighbor::Predecessor,
+    Neighbor::Successor,
+];
+
+/// Returns the entry the given neighbor query of `AvlTree` finds for `probe`.
+pub fn expected_neighbor<K: Ord + Copy, V: Copy>(
+    tree: &AvlTree<K, V>,
+    probe: &K,
+    kind: Neighbor,
+) -> Option<KeyValue<K, V>> {
+    let found = match kind {
+        Neighbor::Floor => tree.floor(probe),
+        Neighbor::Ceiling => tree.ceiling(probe),
+        Neighbor::Predecessor => tree.predecessor(probe),
+        Neighbor::Successor => tree.successor(probe),
+    };
+    found.map(|(&key, &value)| KeyValue { key, value })
+}